@@ -0,0 +1,61 @@
+//! Compare parsing every user-agent against the cached `UserAgentParser`.
+//!
+//! The user-agents are drawn from a skewed distribution, as a handful of
+//! current browser releases make up the bulk of real traffic.
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::hint::black_box;
+
+use skeleton::tags::{UserAgentParser, UserAgentSettings};
+
+// (weight, user-agent)
+const USER_AGENTS: &[(usize, &str)] = &[
+    (30, "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:128.0) Gecko/20100101 Firefox/128.0"),
+    (20, "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36"),
+    (12, "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:128.0) Gecko/20100101 Firefox/128.0"),
+    (10, "Mozilla/5.0 (Android 14; Mobile; rv:128.0) Gecko/128.0 Firefox/128.0"),
+    (8, "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0"),
+    (6, "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) FxiOS/125.0 Mobile/15E148 Safari/605.1.15"),
+    (4, "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15"),
+    (3, "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:115.0) Gecko/20100101 Firefox/115.0"),
+    (2, "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:130.0) Gecko/20100101 Firefox/130.0a1"),
+    (2, "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36 Edg/124.0.0.0"),
+    (1, "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"),
+    (1, "curl/8.5.0"),
+    (1, "python-requests/2.31.0"),
+];
+
+fn requests() -> Vec<&'static str> {
+    USER_AGENTS
+        .iter()
+        .flat_map(|(weight, ua)| std::iter::repeat_n(*ua, *weight))
+        .collect()
+}
+
+fn bench_user_agent(c: &mut Criterion) {
+    let requests = requests();
+    let uncached = UserAgentParser::new(&UserAgentSettings {
+        cache_size: 0,
+        ..Default::default()
+    });
+    let cached = UserAgentParser::new(&UserAgentSettings::default());
+
+    let mut group = c.benchmark_group("user_agent");
+    group.bench_function("uncached", |b| {
+        b.iter(|| {
+            for ua in &requests {
+                black_box(uncached.tags(black_box(ua)));
+            }
+        })
+    });
+    group.bench_function("cached", |b| {
+        b.iter(|| {
+            for ua in &requests {
+                black_box(cached.tags(black_box(ua)));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_user_agent);
+criterion_main!(benches);
@@ -1,20 +1,74 @@
 use std::{error::Error, fmt, result};
 
 use actix_web::{
-    dev::ServiceResponse, error::ResponseError, http::StatusCode, middleware::ErrorHandlerResponse,
-    HttpResponse, HttpResponseBuilder, Result,
+    body::EitherBody,
+    dev::ServiceResponse,
+    error::ResponseError,
+    http::{
+        header::{HeaderName, HeaderValue, RETRY_AFTER, WWW_AUTHENTICATE},
+        StatusCode,
+    },
+    middleware::ErrorHandlerResponse,
+    web::Data,
+    HttpMessage, HttpResponse, Result,
 };
 use backtrace::Backtrace;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use thiserror::Error;
 
+use crate::{metrics::Metrics, server::ServerState, web::middleware::request_id::RequestId};
+
 // pub type Result<T> = result::Result<T, HandlerError>;
 
 pub type HandlerResult<T> = result::Result<T, HandlerError>;
 
+/// How error responses are rendered to the client.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorFormat {
+    /// Only the bare `errno` integer (Sync 1.1 backwards compatibility)
+    #[default]
+    Errno,
+    /// A JSON object containing the `errno`, `code`, `message`, etc.
+    Json,
+    /// An RFC 7807 `application/problem+json` object
+    Problem,
+}
+
 #[derive(Debug)]
 pub struct HandlerError {
     kind: HandlerErrorKind,
     backtrace: Backtrace,
+    details: Option<Value>,
+    request_id: Option<String>,
+}
+
+/// The descriptive error body returned for `ErrorFormat::Json`.
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub code: u16,
+    pub errno: i32,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// The RFC 7807 error body returned for `ErrorFormat::Problem`.
+#[derive(Debug, Serialize)]
+pub struct ProblemBody {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub errno: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
 }
 
 #[derive(Clone, Eq, PartialEq, Debug, Error)]
@@ -23,35 +77,119 @@ pub enum HandlerErrorKind {
     General(String),
     #[error("Internal error: {:?}", _0)]
     Internal(String),
+    /// The request was malformed or failed validation
+    #[error("Bad request: {}", _0)]
+    BadRequest(String),
+    #[error("Not found: {}", _0)]
+    NotFound(String),
+    /// The request lacks valid credentials, with the `WWW-Authenticate`
+    /// challenge for the scheme expected (e.g. `Bearer realm="api"`)
+    #[error("Unauthorized: {}", _0)]
+    Unauthorized(String, String),
+    #[error("Forbidden: {}", _0)]
+    Forbidden(String),
+    #[error("Conflict: {}", _0)]
+    Conflict(String),
+    #[error("Payload too large: {}", _0)]
+    PayloadTooLarge(String),
+    /// The client should wait the optional number of seconds before retrying
+    #[error("Too many requests")]
+    TooManyRequests(Option<u64>),
+    /// The service (or one of its dependencies) is temporarily unavailable,
+    /// with an optional number of seconds to wait before retrying
+    #[error("Service unavailable: {}", _0)]
+    ServiceUnavailable(String, Option<u64>),
 }
 
 impl HandlerErrorKind {
     /// Return a response Status to be rendered for an error
     pub fn http_status(&self) -> StatusCode {
         match self {
-            // HandlerErrorKind::NotFound => Status::NotFound,
             HandlerErrorKind::Internal(_) | HandlerErrorKind::General(_) => {
                 StatusCode::INTERNAL_SERVER_ERROR
-            } // _ => StatusCode::UNAUTHORIZED,
+            }
+            HandlerErrorKind::BadRequest(_) => StatusCode::BAD_REQUEST,
+            HandlerErrorKind::NotFound(_) => StatusCode::NOT_FOUND,
+            HandlerErrorKind::Unauthorized(..) => StatusCode::UNAUTHORIZED,
+            HandlerErrorKind::Forbidden(_) => StatusCode::FORBIDDEN,
+            HandlerErrorKind::Conflict(_) => StatusCode::CONFLICT,
+            HandlerErrorKind::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            HandlerErrorKind::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            HandlerErrorKind::ServiceUnavailable(..) => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 
     /// Return a unique errno code
+    ///
+    /// These are part of the public API and must not change once assigned.
     pub fn errno(&self) -> i32 {
         match self {
             HandlerErrorKind::Internal(_) => 510,
             HandlerErrorKind::General(_) => 500,
+            HandlerErrorKind::BadRequest(_) => 400,
+            HandlerErrorKind::Unauthorized(..) => 401,
+            HandlerErrorKind::Forbidden(_) => 403,
+            HandlerErrorKind::NotFound(_) => 404,
+            HandlerErrorKind::Conflict(_) => 409,
+            HandlerErrorKind::PayloadTooLarge(_) => 413,
+            HandlerErrorKind::TooManyRequests(_) => 429,
+            HandlerErrorKind::ServiceUnavailable(..) => 503,
+        }
+    }
+
+    /// Return any additional headers to include in the error response
+    pub fn headers(&self) -> Vec<(HeaderName, HeaderValue)> {
+        match self {
+            HandlerErrorKind::Unauthorized(_, challenge) => HeaderValue::from_str(challenge)
+                .map(|challenge| vec![(WWW_AUTHENTICATE, challenge)])
+                .unwrap_or_default(),
+            HandlerErrorKind::TooManyRequests(Some(retry_after))
+            | HandlerErrorKind::ServiceUnavailable(_, Some(retry_after)) => {
+                vec![(RETRY_AFTER, HeaderValue::from(*retry_after))]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Whether this error should be reported to Sentry.
+    ///
+    /// Client errors are an expected part of normal operation, so they (and
+    /// other "noisy" errors) are only recorded as a metric via `on_response`.
+    pub fn is_reportable(&self) -> bool {
+        match self {
+            HandlerErrorKind::General(_) | HandlerErrorKind::Internal(_) => true,
+            HandlerErrorKind::BadRequest(_)
+            | HandlerErrorKind::NotFound(_)
+            | HandlerErrorKind::Unauthorized(..)
+            | HandlerErrorKind::Forbidden(_)
+            | HandlerErrorKind::Conflict(_)
+            | HandlerErrorKind::PayloadTooLarge(_)
+            | HandlerErrorKind::TooManyRequests(_)
+            | HandlerErrorKind::ServiceUnavailable(..) => false,
+        }
+    }
+
+    /// The metric to increment when this error is returned, if any.
+    pub fn metric_label(&self) -> Option<&'static str> {
+        match self {
+            HandlerErrorKind::General(_) | HandlerErrorKind::Internal(_) => None,
+            HandlerErrorKind::BadRequest(_) => Some("error.bad_request"),
+            HandlerErrorKind::NotFound(_) => Some("error.not_found"),
+            HandlerErrorKind::Unauthorized(..) => Some("error.unauthorized"),
+            HandlerErrorKind::Forbidden(_) => Some("error.forbidden"),
+            HandlerErrorKind::Conflict(_) => Some("error.conflict"),
+            HandlerErrorKind::PayloadTooLarge(_) => Some("error.payload_too_large"),
+            HandlerErrorKind::TooManyRequests(_) => Some("error.too_many_requests"),
+            HandlerErrorKind::ServiceUnavailable(..) => Some("error.service_unavailable"),
         }
     }
 
-    /*
-    // Optionally record metric for certain states
-    pub fn on_response(&self, state: &ServerState) {
-        if self.is_conflict() {
-            Metrics::from(state).incr("storage.confict")
+    /// Optionally record metric for certain states
+    pub fn on_response(&self, state: &Data<ServerState>) {
+        if let Some(label) = self.metric_label() {
+            Metrics::from(state).incr(label);
         }
     }
-    */
 }
 
 impl ResponseError for HandlerErrorKind {
@@ -66,9 +204,65 @@ impl HandlerError {
         &self.kind
     }
 
+    pub fn is_reportable(&self) -> bool {
+        self.kind.is_reportable()
+    }
+
+    pub fn on_response(&self, state: &Data<ServerState>) {
+        self.kind.on_response(state)
+    }
+
     pub fn internal(msg: &str) -> Self {
         HandlerErrorKind::Internal(msg.to_owned()).into()
     }
+
+    /// Include additional, client safe, information in the error body.
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn with_request_id(mut self, request_id: &str) -> Self {
+        self.request_id = Some(request_id.to_owned());
+        self
+    }
+
+    /// The message presented to the client. Server errors may contain
+    /// internal details, so only their generic reason is returned.
+    pub fn message(&self) -> String {
+        let status = self.status_code();
+        if status.is_server_error() {
+            status
+                .canonical_reason()
+                .unwrap_or("Internal Server Error")
+                .to_owned()
+        } else {
+            self.kind.to_string()
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody {
+            code: self.status_code().as_u16(),
+            errno: self.kind.errno(),
+            message: self.message(),
+            details: self.details.clone(),
+            request_id: self.request_id.clone(),
+        }
+    }
+
+    pub fn problem(&self) -> ProblemBody {
+        let status = self.status_code();
+        ProblemBody {
+            problem_type: "about:blank".to_owned(),
+            title: status.canonical_reason().unwrap_or("Unknown").to_owned(),
+            status: status.as_u16(),
+            detail: self.message(),
+            errno: self.kind.errno(),
+            details: self.details.clone(),
+            request_id: self.request_id.clone(),
+        }
+    }
 }
 
 impl Error for HandlerError {
@@ -79,12 +273,63 @@ impl Error for HandlerError {
 
 impl HandlerError {
     pub fn render_404<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>> {
-        // Replace the outbound error message with our own.
-        let resp = HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish();
+        // Keep any response already rendered from one of our errors.
+        if let Some(err) = res.response().error() {
+            if err.as_error::<HandlerError>().is_some() {
+                return Self::render_error(res);
+            }
+        }
+        // Replace the outbound error message with our own, keeping the error
+        // for the middleware (e.g. the access log's `errno`).
+        let resp = HttpResponse::from_error(HandlerError::from(HandlerErrorKind::NotFound(
+            res.request().path().to_owned(),
+        )));
+        let res = in_configured_format(res.into_response(resp));
         Ok(ErrorHandlerResponse::Response(
-            res.into_response(resp).map_into_right_body(),
+            res.map_into_boxed_body().map_into_right_body(),
         ))
     }
+
+    /// Render our errors' responses in the configured `ErrorFormat`, including
+    /// the request's ID (the `ErrorHandlers` default handler).
+    pub fn render_error<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>> {
+        Ok(ErrorHandlerResponse::Response(in_configured_format(res)))
+    }
+}
+
+/// `ResponseError::error_response` has no access to the app state, so it
+/// renders the default format: render the response again if another format is
+/// configured. The error is kept on the response for the outer middleware.
+fn in_configured_format<B>(res: ServiceResponse<B>) -> ServiceResponse<EitherBody<B>> {
+    let format = res
+        .request()
+        .app_data::<Data<ServerState>>()
+        .map(|state| state.error_format)
+        .unwrap_or_default();
+    if format == ErrorFormat::default() {
+        return res.map_into_left_body();
+    }
+    let request_id = res
+        .request()
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone());
+    let rendered = res
+        .response()
+        .error()
+        .and_then(|err| err.as_error::<HandlerError>())
+        .map(|herr| herr.error_response_with(format, request_id.as_deref()));
+    let Some(rendered) = rendered else {
+        return res.map_into_left_body();
+    };
+    let (rendered, body) = rendered.into_parts();
+    res.map_body(|head, _| {
+        for (name, value) in rendered.headers() {
+            head.headers.insert(name.clone(), value.clone());
+        }
+        body
+    })
+    .map_into_right_body()
 }
 
 impl<T> From<T> for HandlerError
@@ -95,6 +340,8 @@ where
         HandlerError {
             kind: HandlerErrorKind::from(item),
             backtrace: Backtrace::new(),
+            details: None,
+            request_id: None,
         }
     }
 }
@@ -120,19 +367,173 @@ impl fmt::Display for HandlerError {
     }
 }
 
+impl HandlerError {
+    /// Render the error response in the given format, for the identified
+    /// request (unless the error already names one).
+    pub fn error_response_with(
+        &self,
+        format: ErrorFormat,
+        request_id: Option<&str>,
+    ) -> HttpResponse {
+        let request_id = self.request_id.as_deref().or(request_id);
+        let mut resp = HttpResponse::build(self.status_code());
+        for header in self.kind().headers() {
+            resp.insert_header(header);
+        }
+        match format {
+            // Retain Sync 1.1 backwards compatibility as the Python one does.
+            ErrorFormat::Errno => resp.json(self.kind().errno()),
+            ErrorFormat::Json => resp.json(ErrorBody {
+                request_id: request_id.map(str::to_owned),
+                ..self.body()
+            }),
+            ErrorFormat::Problem => match serde_json::to_string(&ProblemBody {
+                request_id: request_id.map(str::to_owned),
+                ..self.problem()
+            }) {
+                Ok(body) => resp.content_type("application/problem+json").body(body),
+                Err(_) => resp.json(self.kind().errno()),
+            },
+        }
+    }
+}
+
 impl ResponseError for HandlerError {
+    /// Rendered in the default format: `HandlerError::render_error` renders
+    /// the response again in the configured `ErrorFormat`, with the request's
+    /// ID.
     fn error_response(&self) -> HttpResponse {
-        // To return a descriptive error response, this would work. We do not
-        // unfortunately do that so that we can retain Sync 1.1 backwards compatibility
-        // as the Python one does.
-        // HttpResponse::build(self.status).json(self)
-        //
-        // So instead we translate our error to a backwards compatible one
-        let mut resp = HttpResponse::build(self.status_code());
-        resp.json(self.kind().errno())
+        self.error_response_with(ErrorFormat::default(), None)
     }
 
     fn status_code(&self) -> StatusCode {
         self.kind().http_status()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{body::to_bytes, http::header::CONTENT_TYPE};
+
+    async fn render(err: &HandlerError, format: ErrorFormat) -> (String, Value) {
+        render_for(err, format, None).await
+    }
+
+    async fn render_for(
+        err: &HandlerError,
+        format: ErrorFormat,
+        request_id: Option<&str>,
+    ) -> (String, Value) {
+        let resp = err.error_response_with(format, request_id);
+        let content_type = resp.headers().get(CONTENT_TYPE).unwrap();
+        let content_type = content_type.to_str().unwrap().to_owned();
+        let body = to_bytes(resp.into_body()).await.unwrap();
+        (content_type, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[actix_rt::test]
+    async fn error_formats() {
+        let err = HandlerError::internal("db password leaked")
+            .with_details(serde_json::json!({"field": "uid"}))
+            .with_request_id("abc123");
+
+        let (_, body) = render(&err, ErrorFormat::Errno).await;
+        assert_eq!(body, serde_json::json!(510));
+
+        let (_, body) = render(&err, ErrorFormat::Json).await;
+        assert_eq!(body["code"], 500);
+        assert_eq!(body["errno"], 510);
+        assert_eq!(body["message"], "Internal Server Error");
+        assert_eq!(body["details"]["field"], "uid");
+        assert_eq!(body["request_id"], "abc123");
+
+        let (content_type, body) = render(&err, ErrorFormat::Problem).await;
+        assert_eq!(content_type, "application/problem+json");
+        assert_eq!(body["status"], 500);
+        assert_eq!(body["title"], "Internal Server Error");
+        assert_eq!(body["errno"], 510);
+        assert_eq!(body["request_id"], "abc123");
+
+        // The ID of the request is added by the `RequestIdWrapper`.
+        let err = HandlerError::from(HandlerErrorKind::Conflict("busy".to_owned()));
+        let (_, body) = render(&err, ErrorFormat::Json).await;
+        assert!(body.get("request_id").is_none());
+        let (_, body) = render_for(&err, ErrorFormat::Problem, Some("def456")).await;
+        assert_eq!(body["errno"], 409);
+        assert_eq!(body["request_id"], "def456");
+    }
+
+    #[actix_rt::test]
+    async fn configured_format() {
+        use actix_web::{
+            http::header::AUTHORIZATION,
+            middleware::ErrorHandlers,
+            test::{call_service, init_service, TestRequest},
+            web, App,
+        };
+
+        use crate::settings::Settings;
+
+        async fn fail() -> HandlerResult<String> {
+            Err(HandlerErrorKind::Conflict("busy".to_owned()).into())
+        }
+
+        let settings = Settings {
+            error_format: ErrorFormat::Problem,
+            ..Default::default()
+        };
+        let state = ServerState::from_settings(&settings).unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .wrap(
+                    ErrorHandlers::new()
+                        .handler(StatusCode::NOT_FOUND, HandlerError::render_404)
+                        .default_handler(HandlerError::render_error),
+                )
+                .route("/fail", web::get().to(fail)),
+        )
+        .await;
+        for (path, errno) in [("/fail", 409), ("/missing", 404)] {
+            let req = TestRequest::get()
+                .uri(path)
+                .insert_header((AUTHORIZATION, "Bearer abc"))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            // Still available to the outer middleware.
+            let herr = resp.response().error().unwrap().as_error::<HandlerError>();
+            assert_eq!(herr.unwrap().kind().errno(), errno);
+            assert_eq!(
+                resp.headers().get(CONTENT_TYPE).unwrap(),
+                "application/problem+json"
+            );
+            let body = to_bytes(resp.into_body()).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["errno"], errno);
+        }
+    }
+
+    #[test]
+    fn error_headers() {
+        let resp = HandlerError::from(HandlerErrorKind::TooManyRequests(Some(30))).error_response();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "30");
+
+        let resp = HandlerError::from(HandlerErrorKind::Unauthorized(
+            "no token".to_owned(),
+            "Basic realm=\"admin\"".to_owned(),
+        ))
+        .error_response();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            resp.headers().get(WWW_AUTHENTICATE).unwrap(),
+            "Basic realm=\"admin\""
+        );
+
+        let resp = HandlerError::from(HandlerErrorKind::ServiceUnavailable("db".to_owned(), None))
+            .error_response();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!resp.headers().contains_key(RETRY_AFTER));
+    }
+}
@@ -8,6 +8,7 @@ extern crate slog_scope;
 pub mod logging;
 pub mod error;
 pub mod metrics;
+pub mod scrub;
 pub mod server;
 pub mod settings;
 pub mod tags;
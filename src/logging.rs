@@ -1,12 +1,100 @@
-use std::io;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    task::{Context, Poll},
+};
 
-use slog::{slog_o, Drain};
+use sentry::integrations::slog::{LevelFilter, SentryDrain};
+use serde::Deserialize;
+use slog::{slog_o, Drain, Never, SendSyncRefUnwindSafeDrain};
 use slog_mozlog_json::MozLogJson;
 
-use crate::error::HandlerResult;
+use crate::{error::HandlerResult, server::reporting::SentrySettings};
 
-pub fn init_logging(json: bool) -> HandlerResult<()> {
-    let logger = if json {
+/// The root logger of the access log, see [access_logger].
+static ACCESS_LOGGER: OnceLock<slog::Logger> = OnceLock::new();
+
+/// A minimum log level, or `off`.
+///
+/// Note that `debug` and `trace` records are compiled out (slog's
+/// `max_level_info` feature), so those levels behave like `info`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Critical,
+    Error,
+    Warning,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    /// Whether records of the given level are at least this severe.
+    pub fn includes(&self, level: slog::Level) -> bool {
+        let min = match self {
+            LogLevel::Off => return false,
+            LogLevel::Critical => slog::Level::Critical,
+            LogLevel::Error => slog::Level::Error,
+            LogLevel::Warning => slog::Level::Warning,
+            LogLevel::Info => slog::Level::Info,
+            LogLevel::Debug => slog::Level::Debug,
+            LogLevel::Trace => slog::Level::Trace,
+        };
+        level.is_at_least(min)
+    }
+}
+
+/// Forward log records to Sentry: as events from `log_event_level`, or as
+/// breadcrumbs (attached to any later event) from `log_breadcrumb_level`.
+/// The record's key/values (e.g. `Tags`) are included as extra data.
+pub fn sentry_drain<D: Drain>(drain: D, settings: &SentrySettings) -> SentryDrain<D> {
+    let events = settings.log_event_level;
+    let breadcrumbs = settings.log_breadcrumb_level;
+    SentryDrain::new(drain).filter(move |level| {
+        if events.includes(level) {
+            LevelFilter::Event
+        } else if breadcrumbs.includes(level) {
+            LevelFilter::Breadcrumb
+        } else {
+            LevelFilter::Ignore
+        }
+    })
+}
+
+/// The root logger, forwarding to Sentry, and the access log's logger, which
+/// shares its drain but never forwards to Sentry: logged outside of the
+/// request's hub, its records would be attached to other requests' events.
+fn root_loggers<D>(drain: D, sentry: &SentrySettings) -> (slog::Logger, slog::Logger)
+where
+    D: SendSyncRefUnwindSafeDrain<Ok = (), Err = Never> + 'static,
+{
+    let drain = Arc::new(drain);
+    (
+        slog::Logger::root(sentry_drain(drain.clone(), sentry), slog_o!()),
+        slog::Logger::root(drain, slog_o!()),
+    )
+}
+
+/// The logger for the access log's `request.summary` records.
+///
+/// Until logging is initialized (e.g. in tests) this is the current
+/// `slog_scope` logger.
+pub fn access_logger() -> slog::Logger {
+    ACCESS_LOGGER
+        .get()
+        .cloned()
+        .unwrap_or_else(slog_scope::logger)
+}
+
+pub fn init_logging(json: bool, sentry: &SentrySettings) -> HandlerResult<()> {
+    // The Sentry drain wraps the async drain, so it runs synchronously on the
+    // thread logging the record, with that thread's current (e.g. the
+    // request's) Sentry hub.
+    let (logger, access_logger) = if json {
         let hostname = hostname::get()
             .expect("Couldn't get hostname")
             .into_string()
@@ -24,14 +112,15 @@ pub fn init_logging(json: bool) -> HandlerResult<()> {
             .fuse();
         let drain = slog_envlogger::new(drain);
         let drain = slog_async::Async::new(drain).build().fuse();
-        slog::Logger::root(drain, slog_o!())
+        root_loggers(drain, sentry)
     } else {
         let decorator = slog_term::TermDecorator::new().build();
         let drain = slog_term::FullFormat::new(decorator).build().fuse();
         let drain = slog_envlogger::new(drain);
         let drain = slog_async::Async::new(drain).build().fuse();
-        slog::Logger::root(drain, slog_o!())
+        root_loggers(drain, sentry)
     };
+    ACCESS_LOGGER.get_or_init(|| access_logger);
     // XXX: cancel slog_scope's NoGlobalLoggerSet for now, it's difficult to
     // prevent it from potentially panicing during tests. reset_logging resets
     // the global logger during shutdown anyway:
@@ -41,7 +130,146 @@ pub fn init_logging(json: bool) -> HandlerResult<()> {
     Ok(())
 }
 
+/// A future run with the given logger as the `slog_scope` logger, so that
+/// the scope's macros (`info!` etc.) used within it log through it.
+pub struct WithLogger<F> {
+    logger: slog::Logger,
+    inner: F,
+}
+
+impl<F> WithLogger<F> {
+    pub fn new(logger: slog::Logger, inner: F) -> Self {
+        Self { logger, inner }
+    }
+}
+
+impl<F: Future + Unpin> Future for WithLogger<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        slog_scope::scope(&this.logger, || Pin::new(&mut this.inner).poll(cx))
+    }
+}
+
 pub fn reset_logging() {
     let logger = slog::Logger::root(slog::Discard, slog_o!());
     slog_scope::set_global_logger(logger).cancel_reset();
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{
+        collections::HashMap,
+        fmt,
+        sync::{Arc, Mutex},
+    };
+
+    use slog::{error, info, warn, Key, Never, OwnedKVList, Record, Serializer, KV};
+
+    use crate::tags::Tags;
+
+    /// Collects key/values, formatted as strings (`None` as empty).
+    #[derive(Default)]
+    pub struct KvCollector(pub HashMap<String, String>);
+
+    impl Serializer for KvCollector {
+        fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments<'_>) -> slog::Result {
+            self.0.insert(key.to_string(), val.to_string());
+            Ok(())
+        }
+    }
+
+    /// A record's message and key/values (including the logger's).
+    pub type Captured = (String, HashMap<String, String>);
+
+    /// Captures each record logged.
+    #[derive(Clone, Default)]
+    pub struct CaptureDrain(Arc<Mutex<Vec<Captured>>>);
+
+    impl CaptureDrain {
+        pub fn records(&self) -> Vec<Captured> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl Drain for CaptureDrain {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, record: &Record<'_>, values: &OwnedKVList) -> Result<(), Never> {
+            let mut kv = KvCollector::default();
+            values.serialize(record, &mut kv).unwrap();
+            record.kv().serialize(record, &mut kv).unwrap();
+            self.0
+                .lock()
+                .unwrap()
+                .push((record.msg().to_string(), kv.0));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn logs_to_sentry() {
+        let settings = SentrySettings {
+            log_event_level: LogLevel::Error,
+            log_breadcrumb_level: LogLevel::Warning,
+            ..Default::default()
+        };
+        let logger = slog::Logger::root(sentry_drain(slog::Discard, &settings), slog_o!());
+        let tags = Tags::with_tags([("uri.method".to_owned(), "GET".to_owned())].into());
+        let events = sentry::test::with_captured_events(|| {
+            info!(logger, "ignored");
+            warn!(logger, "a breadcrumb"; "attempt" => 2);
+            error!(logger, "an event"; tags);
+        });
+
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.message.as_deref(), Some("an event"));
+        assert_eq!(event.level, sentry::Level::Error);
+        assert_eq!(event.extra["uri.method"], "GET");
+        let crumbs: Vec<_> = event.breadcrumbs.iter().collect();
+        assert_eq!(crumbs.len(), 1);
+        assert_eq!(crumbs[0].message.as_deref(), Some("a breadcrumb"));
+        assert_eq!(crumbs[0].data["attempt"], 2);
+    }
+
+    #[test]
+    fn off_by_default() {
+        let logger = slog::Logger::root(
+            sentry_drain(slog::Discard, &SentrySettings::default()),
+            slog_o!(),
+        );
+        let events = sentry::test::with_captured_events(|| {
+            error!(logger, "not an event");
+        });
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn access_log_not_sent_to_sentry() {
+        use crate::web::middleware::access_log::REQUEST_SUMMARY;
+
+        let settings = SentrySettings {
+            log_event_level: LogLevel::Error,
+            log_breadcrumb_level: LogLevel::Info,
+            ..Default::default()
+        };
+        let (logger, access_logger) = root_loggers(slog::Discard, &settings);
+        let events = sentry::test::with_captured_events(|| {
+            info!(access_logger, "{}", REQUEST_SUMMARY; "code" => 200);
+            info!(logger, "a breadcrumb");
+            error!(logger, "an event");
+        });
+
+        assert_eq!(events.len(), 1);
+        let crumbs: Vec<_> = events[0]
+            .breadcrumbs
+            .iter()
+            .filter_map(|crumb| crumb.message.as_deref())
+            .collect();
+        assert_eq!(crumbs, ["a breadcrumb"]);
+    }
+}
@@ -31,28 +31,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
     let settings = settings::Settings::with_env_and_config_file(&args.flag_config)?;
-    init_logging(!settings.human_logs).expect("Logging failed to init");
+    init_logging(!settings.human_logs, &settings.sentry).expect("Logging failed to init");
     debug!("Starting up...");
-    // Set SENTRY_DSN env var to enable Sentry.actix_cors
-    // Avoid its default reqwest transport for now due to issues w/
-    // likely grpcio's boringssl
-    /*
-    let curl_transport_factory = |options: &sentry::ClientOptions| {
-        Arc::new(sentry::transports::CurlHttpTransport::new(&options))
-            as Arc<dyn sentry::internals::Transport>
-    };
-    */
-    let _sentry = sentry::init(sentry::ClientOptions {
-        // Note: set "debug: true," to diagnose sentry issues
-        // transport: Some(Arc::new(curl_transport_factory)),
-        release: sentry::release_name!(),
-        ..sentry::ClientOptions::default()
-    });
-
-    debug!("Starting up...");
-
     let banner = settings.banner();
-    let server = server::Server::with_settings(settings).await.unwrap();
+    // Sentry is configured from the `sentry` settings (or the SENTRY_DSN env var).
+    let server = server::Server::with_settings(settings).await?;
     info!("Server running on {}", banner);
     server.await?;
     info!("Server closing");
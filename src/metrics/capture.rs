@@ -0,0 +1,102 @@
+//! Capture emitted metrics in memory, so tests can make assertions about them.
+//!
+//! ```ignore
+//! let (state, capture) = ServerState::capturing(&Settings::default())?;
+//! let app = init_service(build_app!(Data::new(state))).await;
+//! call_service(&app, TestRequest::get().uri("/foo").to_request()).await;
+//! capture.assert_metric_emitted("foo.bar", &[("ua.browser.family", "Firefox")]);
+//! ```
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use cadence::MetricSink;
+
+use super::line::MetricLine;
+
+/// A cadence `MetricSink` recording every metric. Clones share the same
+/// recorded metrics.
+#[derive(Clone, Debug, Default)]
+pub struct MetricsCapture {
+    /// Stripped from the recorded metric names
+    prefix: String,
+    lines: Arc<Mutex<Vec<MetricLine>>>,
+}
+
+impl MetricsCapture {
+    /// Record metrics from a client using the given prefix (e.g. the
+    /// `statsd_label`). The prefix is removed from the recorded names.
+    pub fn with_prefix(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// All of the metrics recorded so far.
+    pub fn metrics(&self) -> Vec<MetricLine> {
+        self.lines.lock().expect("Metrics capture poisoned").clone()
+    }
+
+    /// The recorded metrics with the given name.
+    pub fn find(&self, name: &str) -> Vec<MetricLine> {
+        self.metrics()
+            .into_iter()
+            .filter(|line| line.name == name)
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.lines.lock().expect("Metrics capture poisoned").clear();
+    }
+
+    /// Was a metric with the given name and (at least) these tags recorded?
+    pub fn emitted(&self, name: &str, tags: &[(&str, &str)]) -> bool {
+        self.find(name).iter().any(|line| {
+            tags.iter()
+                .all(|(k, v)| line.tags.get(*k).map(String::as_str) == Some(*v))
+        })
+    }
+
+    /// Panic unless a metric with the given name and tags was recorded.
+    pub fn assert_metric_emitted(&self, name: &str, tags: &[(&str, &str)]) {
+        assert!(
+            self.emitted(name, tags),
+            "Metric {:?} with tags {:?} not emitted. Recorded: {:#?}",
+            name,
+            tags,
+            self.metrics()
+        );
+    }
+
+    /// Panic if a metric with the given name and tags was recorded.
+    pub fn assert_metric_not_emitted(&self, name: &str, tags: &[(&str, &str)]) {
+        assert!(
+            !self.emitted(name, tags),
+            "Metric {:?} with tags {:?} unexpectedly emitted",
+            name,
+            tags
+        );
+    }
+}
+
+impl MetricSink for MetricsCapture {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let mut line = metric
+            .parse::<MetricLine>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if let Some(name) = line
+            .name
+            .strip_prefix(&self.prefix)
+            .and_then(|name| name.strip_prefix('.'))
+        {
+            line.name = name.to_owned();
+        }
+        self.lines
+            .lock()
+            .expect("Metrics capture poisoned")
+            .push(line);
+        Ok(metric.len())
+    }
+}
@@ -0,0 +1,123 @@
+//! Limit the number of distinct values each tag may have.
+//!
+//! Every distinct set of tag values creates a new time series in the metrics
+//! backend, so an unbounded value (e.g. a user id accidentally added via
+//! `Tags::extend`) can be very expensive. Values seen after a tag's limit is
+//! reached are replaced with [OVERFLOW_VALUE].
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use serde::Deserialize;
+
+use crate::tags::Tags;
+
+/// Replacement for any tag value past the limit
+pub const OVERFLOW_VALUE: &str = "other";
+/// Counter incremented whenever a tag value is replaced
+pub const OVERFLOW_METRIC: &str = "metrics.cardinality_overflow";
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct CardinalitySettings {
+    /// The maximum distinct values for any tag not listed in `limits`
+    /// (unlimited if not set)
+    pub default_limit: Option<usize>,
+    /// The maximum distinct values for specific tags
+    pub limits: HashMap<String, usize>,
+}
+
+impl Default for CardinalitySettings {
+    fn default() -> Self {
+        Self {
+            default_limit: Some(1000),
+            limits: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CardinalityLimiter {
+    settings: CardinalitySettings,
+    seen: Mutex<HashMap<String, HashSet<String>>>,
+}
+
+impl CardinalityLimiter {
+    pub fn new(settings: &CardinalitySettings) -> Self {
+        Self {
+            settings: settings.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn limit_for(&self, key: &str) -> Option<usize> {
+        self.settings
+            .limits
+            .get(key)
+            .copied()
+            .or(self.settings.default_limit)
+    }
+
+    /// Replace any tag values past their limit, returning the keys of the
+    /// replaced tags.
+    pub fn apply(&self, tags: &mut Tags) -> Vec<String> {
+        let mut overflowed = vec![];
+        let mut seen = self.seen.lock().expect("Cardinality limiter poisoned");
+        for (key, value) in tags.tags.iter_mut() {
+            let Some(limit) = self.limit_for(key) else {
+                continue;
+            };
+            let values = seen.entry(key.clone()).or_default();
+            if values.contains(value.as_str()) {
+                continue;
+            }
+            if values.len() < limit {
+                values.insert(value.clone());
+                continue;
+            }
+            // Only warn the first time; the counter records every occurrence.
+            if !values.contains(OVERFLOW_VALUE) {
+                warn!(
+                    "⚠️ Metric tag {:?} exceeded {} distinct values, further values reported as {:?}",
+                    key, limit, OVERFLOW_VALUE
+                );
+                values.insert(OVERFLOW_VALUE.to_owned());
+            }
+            *value = OVERFLOW_VALUE.to_owned();
+            overflowed.push(key.clone());
+        }
+        overflowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(key: &str, value: &str) -> Tags {
+        let mut tags = HashMap::new();
+        tags.insert(key.to_owned(), value.to_owned());
+        Tags::with_tags(tags)
+    }
+
+    #[test]
+    fn collapses_values_past_limit() {
+        let mut settings = CardinalitySettings::default();
+        settings.limits.insert("uid".to_owned(), 2);
+        let limiter = CardinalityLimiter::new(&settings);
+
+        for uid in ["1", "2", "1"] {
+            let mut t = tags("uid", uid);
+            assert!(limiter.apply(&mut t).is_empty());
+            assert_eq!(t.get("uid"), uid);
+        }
+        let mut t = tags("uid", "3");
+        assert_eq!(limiter.apply(&mut t), vec!["uid".to_owned()]);
+        assert_eq!(t.get("uid"), OVERFLOW_VALUE);
+
+        // Other tags use the default limit.
+        let mut t = tags("ua.os.family", "Linux");
+        assert!(limiter.apply(&mut t).is_empty());
+    }
+}
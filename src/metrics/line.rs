@@ -0,0 +1,104 @@
+//! Parsing of the statsd metric lines generated by cadence.
+//!
+//! In-process sinks (e.g. the Prometheus registry) receive the same
+//! DogStatsD formatted lines that would be sent to the statsd server. This
+//! parses those back into their parts.
+use std::{collections::BTreeMap, str::FromStr};
+
+/// The statsd metric types cadence can emit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MetricKind {
+    Counter,
+    Timer,
+    Gauge,
+    Meter,
+    Histogram,
+    Distribution,
+    Set,
+}
+
+impl FromStr for MetricKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "c" => MetricKind::Counter,
+            "ms" => MetricKind::Timer,
+            "g" => MetricKind::Gauge,
+            "m" => MetricKind::Meter,
+            "h" => MetricKind::Histogram,
+            "d" => MetricKind::Distribution,
+            "s" => MetricKind::Set,
+            _ => return Err(format!("Unknown metric type: {:?}", s)),
+        })
+    }
+}
+
+/// A single parsed metric, e.g. `skeleton.foo:1|c|@0.5|#ua.os.family:Linux`
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricLine {
+    pub name: String,
+    pub kind: MetricKind,
+    pub value: f64,
+    pub sample_rate: Option<f64>,
+    pub tags: BTreeMap<String, String>,
+}
+
+impl FromStr for MetricLine {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("Invalid metric line: {:?}", line);
+        let (name, rest) = line.split_once(':').ok_or_else(bad)?;
+        let mut parts = rest.split('|');
+        let value = parts
+            .next()
+            .and_then(|v| v.parse::<f64>().ok())
+            .ok_or_else(bad)?;
+        let kind = parts.next().ok_or_else(bad)?.parse()?;
+        let mut sample_rate = None;
+        let mut tags = BTreeMap::new();
+        for part in parts {
+            if let Some(rate) = part.strip_prefix('@') {
+                sample_rate = Some(rate.parse::<f64>().map_err(|_| bad())?);
+            } else if let Some(tag_list) = part.strip_prefix('#') {
+                for tag in tag_list.split(',').filter(|t| !t.is_empty()) {
+                    let (key, val) = tag.split_once(':').unwrap_or((tag, ""));
+                    tags.insert(key.to_owned(), val.to_owned());
+                }
+            }
+            // container ids (`c:`) and timestamps (`T`) are ignored.
+        }
+        Ok(MetricLine {
+            name: name.to_owned(),
+            kind,
+            value,
+            sample_rate,
+            tags,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_lines() {
+        let line: MetricLine = "skeleton.foo.bar:3|c|@0.5|#ua.os.family:Linux,uri.method:GET"
+            .parse()
+            .unwrap();
+        assert_eq!(line.name, "skeleton.foo.bar");
+        assert_eq!(line.kind, MetricKind::Counter);
+        assert_eq!(line.value, 3.0);
+        assert_eq!(line.sample_rate, Some(0.5));
+        assert_eq!(line.tags.get("uri.method").unwrap(), "GET");
+
+        let line: MetricLine = "timer:12|ms".parse().unwrap();
+        assert_eq!(line.kind, MetricKind::Timer);
+        assert!(line.tags.is_empty());
+
+        assert!("nope".parse::<MetricLine>().is_err());
+        assert!("foo:1|zz".parse::<MetricLine>().is_err());
+    }
+}
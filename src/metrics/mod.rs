@@ -0,0 +1,575 @@
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use actix_web::{web::Data, HttpRequest};
+use cadence::{
+    ext::{ToDistributionValue, ToGaugeValue, ToHistogramValue, ToSetValue},
+    Counted, CountedExt, Distributed, Gauged, Histogrammed, Metric, MetricBuilder, NopMetricSink,
+    QueuingMetricSink, Setted, StatsdClient, Timed,
+};
+use serde::Deserialize;
+
+use crate::{error::HandlerError, server::ServerState, settings::Settings, tags::Tags};
+
+pub mod capture;
+pub mod cardinality;
+pub mod line;
+pub mod prometheus;
+pub mod sink;
+
+use capture::MetricsCapture;
+use cardinality::{CardinalityLimiter, OVERFLOW_METRIC};
+use prometheus::{PrometheusRegistry, PrometheusSink};
+use sink::MultiSink;
+
+/// Where metrics are sent.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsBackend {
+    /// Send to the statsd server at `statsd_host` (if specified)
+    #[default]
+    Statsd,
+    /// Record into an in-process registry, exposed on `/__metrics__`
+    Prometheus,
+    Both,
+}
+
+impl MetricsBackend {
+    pub fn statsd(&self) -> bool {
+        matches!(self, MetricsBackend::Statsd | MetricsBackend::Both)
+    }
+
+    pub fn prometheus(&self) -> bool {
+        matches!(self, MetricsBackend::Prometheus | MetricsBackend::Both)
+    }
+}
+
+/// A running timer, started by `Metrics::start_timer`.
+///
+/// The elapsed time is sent when the timer is `finish`ed, or dropped. The
+/// timer is also recorded as a span of the current Sentry transaction, if any.
+#[derive(Debug)]
+#[must_use = "the timer is sent as soon as it is dropped"]
+pub struct MetricTimer {
+    metrics: Metrics,
+    label: String,
+    start: Instant,
+    tags: Option<Tags>,
+    span: Option<sentry::Span>,
+    finished: bool,
+}
+
+impl MetricTimer {
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Stop the timer and send the result, returning the elapsed time.
+    pub fn finish(mut self) -> Duration {
+        self.send()
+    }
+
+    fn send(&mut self) -> Duration {
+        let elapsed = self.elapsed();
+        if !self.finished {
+            self.finished = true;
+            trace!(
+                "⌚ Ending timer at millis: {:?} : {:?}",
+                &self.label,
+                elapsed.as_millis()
+            );
+            self.metrics.timing_with_tags(
+                &self.label,
+                elapsed.as_millis() as u64,
+                self.tags.take(),
+            );
+            if let Some(span) = self.span.take() {
+                span.finish();
+            }
+        }
+        elapsed
+    }
+}
+
+impl Drop for MetricTimer {
+    fn drop(&mut self) {
+        self.send();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    client: Option<Arc<StatsdClient>>,
+    tags: Option<Tags>,
+    limiter: Option<Arc<CardinalityLimiter>>,
+}
+
+impl From<&HttpRequest> for Metrics {
+    fn from(req: &HttpRequest) -> Self {
+        let tags = Tags::for_request(req);
+        let state = state_from_req(req);
+        Metrics {
+            client: Some(state.metrics.clone()),
+            tags: Some(tags),
+            limiter: Some(state.cardinality.clone()),
+        }
+    }
+}
+
+impl From<StatsdClient> for Metrics {
+    fn from(client: StatsdClient) -> Self {
+        Metrics {
+            client: Some(Arc::new(client)),
+            tags: None,
+            limiter: None,
+        }
+    }
+}
+
+impl From<&actix_web::web::Data<ServerState>> for Metrics {
+    fn from(state: &actix_web::web::Data<ServerState>) -> Self {
+        Metrics {
+            client: Some(state.metrics.clone()),
+            tags: None,
+            limiter: Some(state.cardinality.clone()),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn sink() -> StatsdClient {
+        StatsdClient::builder("", NopMetricSink).build()
+    }
+
+    pub fn noop() -> Self {
+        Self {
+            client: Some(Arc::new(Self::sink())),
+            tags: None,
+            limiter: None,
+        }
+    }
+
+    /// Build a `Metrics` recording everything into the returned capture.
+    pub fn capturing() -> (Self, MetricsCapture) {
+        let capture = MetricsCapture::default();
+        (
+            StatsdClient::builder("", capture.clone()).build().into(),
+            capture,
+        )
+    }
+
+    /// Start a named timer, which is sent when it is finished or dropped.
+    ///
+    /// Any number of timers may run at once, e.g. to separately time the
+    /// database and upstream calls made while handling a request.
+    pub fn start_timer(&self, label: &str, tags: Option<Tags>) -> MetricTimer {
+        trace!("⌚ Starting timer... {:?}", &label);
+        MetricTimer {
+            metrics: self.clone(),
+            label: label.to_owned(),
+            start: Instant::now(),
+            tags,
+            span: sentry::configure_scope(|scope| scope.get_span())
+                .map(|parent| parent.start_child("metrics.timer", label)),
+            finished: false,
+        }
+    }
+
+    /// Time how long the future takes to complete.
+    pub async fn time_future<F: Future>(
+        &self,
+        label: &str,
+        tags: Option<Tags>,
+        fut: F,
+    ) -> F::Output {
+        let timer = self.start_timer(label, tags);
+        let result = fut.await;
+        timer.finish();
+        result
+    }
+
+    /// Limit the cardinality of tags to the given limiter.
+    pub fn with_limiter(mut self, limiter: Arc<CardinalityLimiter>) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// Merge the request tags with any call specific ones.
+    fn merge_tags(&self, tags: Option<Tags>) -> Tags {
+        let mut mtags = self.tags.clone().unwrap_or_default();
+        if let Some(tags) = tags {
+            mtags.extend(tags.tags);
+        }
+        if let (Some(limiter), Some(client)) = (self.limiter.as_ref(), self.client.as_ref()) {
+            for key in limiter.apply(&mut mtags) {
+                let overflow = Tags::with_tags([("tag".to_owned(), key)].into());
+                send_tagged(
+                    OVERFLOW_METRIC,
+                    client.incr_with_tags(OVERFLOW_METRIC),
+                    &overflow,
+                    None,
+                );
+            }
+        }
+        mtags
+    }
+
+    /// Return the client if this metric should be sent, discarding
+    /// `1 - sample_rate` of the calls.
+    fn sampled_client(&self, sample_rate: Option<f64>) -> Option<&StatsdClient> {
+        match sample_rate {
+            Some(rate) if rate < 1.0 && rand::random::<f64>() >= rate => None,
+            _ => self.client.as_deref(),
+        }
+    }
+
+    // increment a counter with no tags data.
+    pub fn incr(&self, label: &str) {
+        self.incr_with_tags(label, None)
+    }
+
+    pub fn incr_with_tags(&self, label: &str, tags: Option<Tags>) {
+        if let Some(client) = self.client.as_ref() {
+            let mtags = self.merge_tags(tags);
+            send_tagged(label, client.incr_with_tags(label), &mtags, None);
+        }
+    }
+
+    pub fn count(&self, label: &str, count: i64) {
+        self.count_with_tags(label, count, None)
+    }
+
+    pub fn count_with_tags(&self, label: &str, count: i64, tags: Option<Tags>) {
+        if let Some(client) = self.client.as_ref() {
+            let mtags = self.merge_tags(tags);
+            send_tagged(label, client.count_with_tags(label, count), &mtags, None);
+        }
+    }
+
+    /// Record an already measured duration, in milliseconds.
+    pub fn timing_with_tags(&self, label: &str, lapse: u64, tags: Option<Tags>) {
+        if let Some(client) = self.client.as_ref() {
+            let mtags = self.merge_tags(tags);
+            send_tagged(label, client.time_with_tags(label, lapse), &mtags, None);
+        }
+    }
+
+    /// Record the current value of something (e.g. a queue depth or pool size).
+    pub fn gauge(&self, label: &str, value: u64) {
+        self.gauge_with_tags(label, value, None, None)
+    }
+
+    pub fn gauge_with_tags<T: ToGaugeValue>(
+        &self,
+        label: &str,
+        value: T,
+        tags: Option<Tags>,
+        sample_rate: Option<f64>,
+    ) {
+        if let Some(client) = self.sampled_client(sample_rate) {
+            let mtags = self.merge_tags(tags);
+            send_tagged(
+                label,
+                client.gauge_with_tags(label, value),
+                &mtags,
+                sample_rate,
+            );
+        }
+    }
+
+    /// Record a value (e.g. a payload size) to be aggregated by the statsd server.
+    pub fn histogram(&self, label: &str, value: u64) {
+        self.histogram_with_tags(label, value, None, None)
+    }
+
+    pub fn histogram_with_tags<T: ToHistogramValue>(
+        &self,
+        label: &str,
+        value: T,
+        tags: Option<Tags>,
+        sample_rate: Option<f64>,
+    ) {
+        if let Some(client) = self.sampled_client(sample_rate) {
+            let mtags = self.merge_tags(tags);
+            send_tagged(
+                label,
+                client.histogram_with_tags(label, value),
+                &mtags,
+                sample_rate,
+            );
+        }
+    }
+
+    /// Record a value to be aggregated globally, across all hosts.
+    pub fn distribution(&self, label: &str, value: u64) {
+        self.distribution_with_tags(label, value, None, None)
+    }
+
+    pub fn distribution_with_tags<T: ToDistributionValue>(
+        &self,
+        label: &str,
+        value: T,
+        tags: Option<Tags>,
+        sample_rate: Option<f64>,
+    ) {
+        if let Some(client) = self.sampled_client(sample_rate) {
+            let mtags = self.merge_tags(tags);
+            send_tagged(
+                label,
+                client.distribution_with_tags(label, value),
+                &mtags,
+                sample_rate,
+            );
+        }
+    }
+
+    /// Record an occurrence of a value, for counting the unique values
+    /// (e.g. users) seen.
+    pub fn set(&self, label: &str, value: i64) {
+        self.set_with_tags(label, value, None, None)
+    }
+
+    pub fn set_with_tags<T: ToSetValue>(
+        &self,
+        label: &str,
+        value: T,
+        tags: Option<Tags>,
+        sample_rate: Option<f64>,
+    ) {
+        if let Some(client) = self.sampled_client(sample_rate) {
+            let mtags = self.merge_tags(tags);
+            send_tagged(
+                label,
+                client.set_with_tags(label, value),
+                &mtags,
+                sample_rate,
+            );
+        }
+    }
+}
+
+/// Add the tags (and sample rate) to the metric and send it.
+fn send_tagged<'a, T>(
+    label: &str,
+    mut tagged: MetricBuilder<'a, 'a, T>,
+    tags: &'a Tags,
+    sample_rate: Option<f64>,
+) where
+    T: Metric + From<String>,
+{
+    for (key, val) in tags.tags.iter() {
+        tagged = tagged.with_tag(key, val);
+    }
+    if let Some(rate) = sample_rate {
+        tagged = tagged.with_sampling_rate(rate);
+    }
+    match tagged.try_send() {
+        Err(e) => {
+            // eat the metric, but log the error
+            warn!("⚠️ Metric {} error: {:?} ", label, e; tags);
+        }
+        Ok(v) => trace!("☑️ {:?}", v.as_metric_str()),
+    }
+}
+
+pub fn metrics_from_req(req: &HttpRequest) -> Arc<StatsdClient> {
+    state_from_req(req).metrics.clone()
+}
+
+fn state_from_req(req: &HttpRequest) -> &Data<ServerState> {
+    req.app_data::<Data<ServerState>>()
+        .expect("Could not get state in metrics_from_req")
+}
+
+/// Create a cadence StatsdClient from the given options
+///
+/// If a Prometheus `registry` is given, all metrics are also recorded there.
+pub fn metrics_from_opts(
+    opts: &Settings,
+    registry: Option<Arc<PrometheusRegistry>>,
+) -> Result<StatsdClient, HandlerError> {
+    Ok(metrics_with_sink(opts, configured_sink(opts, registry)?))
+}
+
+/// The sinks of the configured metrics backends.
+pub fn configured_sink(
+    opts: &Settings,
+    registry: Option<Arc<PrometheusRegistry>>,
+) -> Result<MultiSink, HandlerError> {
+    let mut sink = MultiSink::default();
+    if let Some(statsd_host) = opts
+        .statsd_host
+        .as_ref()
+        .filter(|_| opts.metrics_backend.statsd())
+    {
+        sink.push(QueuingMetricSink::from(sink::statsd_sink(
+            opts,
+            statsd_host,
+        )?));
+    }
+    if let Some(registry) = registry {
+        sink.push(PrometheusSink::new(registry));
+    }
+    Ok(sink)
+}
+
+/// Create a cadence StatsdClient sending to the given sink, using the
+/// prefix and default tags from the options.
+pub fn metrics_with_sink(opts: &Settings, sink: MultiSink) -> StatsdClient {
+    let mut builder = StatsdClient::builder(opts.statsd_label.as_ref(), sink);
+    // Include any "hard coded" tags.
+    for (key, value) in opts.metrics_tags.tags() {
+        builder = builder.with_tag(key, value);
+    }
+    builder
+        .with_error_handler(|err| {
+            warn!("⚠️ Metric send error:  {:?}", err);
+        })
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tags() {
+        use actix_web::dev::RequestHead;
+        use actix_web::http::{header, uri::Uri};
+        use std::collections::HashMap;
+
+        let mut rh = RequestHead::default();
+        let path = "/1.5/42/storage/meta/global";
+        rh.uri = Uri::from_static(path);
+        rh.headers.insert(
+            header::USER_AGENT,
+            header::HeaderValue::from_static(
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:72.0) Gecko/20100101 Firefox/72.0",
+            ),
+        );
+
+        let tags = Tags::from_request_head(&rh);
+
+        let mut result = HashMap::<String, String>::new();
+        result.insert("ua.os.ver".to_owned(), "NT 10.0".to_owned());
+        result.insert("ua.os.family".to_owned(), "Windows".to_owned());
+        result.insert("ua.browser.ver".to_owned(), "72.0".to_owned());
+        result.insert("ua.name".to_owned(), "Firefox".to_owned());
+        result.insert("ua.browser.family".to_owned(), "Firefox".to_owned());
+        result.insert("uri.method".to_owned(), "GET".to_owned());
+
+        assert_eq!(tags.tags, result)
+    }
+
+    #[test]
+    fn no_empty_tags() {
+        use actix_web::dev::RequestHead;
+        use actix_web::http::{header, uri::Uri};
+
+        let mut rh = RequestHead::default();
+        let path = "/1.5/42/storage/meta/global";
+        rh.uri = Uri::from_static(path);
+        rh.headers.insert(
+            header::USER_AGENT,
+            header::HeaderValue::from_static("Mozilla/5.0 (curl) Gecko/20100101 curl"),
+        );
+
+        let tags = Tags::from_request_head(&rh);
+        assert!(!tags.tags.contains_key("ua.os.ver"));
+        println!("{:?}", tags);
+    }
+
+    #[test]
+    fn metric_types() {
+        use cadence::SpyMetricSink;
+        use std::collections::HashMap;
+
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = Metrics::from(StatsdClient::from_sink("skeleton", sink));
+        let mut tags = HashMap::new();
+        tags.insert("queue".to_owned(), "push".to_owned());
+        let tags = Some(Tags::with_tags(tags));
+
+        metrics.gauge_with_tags("queue.depth", 12, tags.clone(), None);
+        metrics.histogram_with_tags("payload.bytes", 1024, tags.clone(), Some(1.0));
+        metrics.distribution("upstream.ms", 7);
+        metrics.set("users", 42);
+        // Never sampled, so never sent.
+        metrics.histogram_with_tags("payload.bytes", 1024, tags, Some(0.0));
+
+        let sent: Vec<String> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert_eq!(
+            sent,
+            vec![
+                "skeleton.queue.depth:12|g|#queue:push",
+                "skeleton.payload.bytes:1024|h|@1|#queue:push",
+                "skeleton.upstream.ms:7|d",
+                "skeleton.users:42|s",
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn named_timers() {
+        use cadence::SpyMetricSink;
+
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = Metrics::from(StatsdClient::from_sink("", sink));
+        let request = metrics.start_timer("request", None);
+        {
+            let _db = metrics.start_timer("db", None);
+            let upstream = metrics.start_timer("upstream", None);
+            upstream.finish();
+        }
+        let answer = metrics.time_future("future", None, async { 42 }).await;
+        assert_eq!(answer, 42);
+        request.finish();
+
+        let sent: Vec<String> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .map(|m| m.split(':').next().unwrap().to_owned())
+            .collect();
+        assert_eq!(sent, vec!["upstream", "db", "future", "request"]);
+    }
+
+    #[test]
+    fn cardinality_overflow() {
+        use cardinality::{CardinalitySettings, OVERFLOW_VALUE};
+        use std::collections::HashMap;
+
+        let (metrics, capture) = Metrics::capturing();
+        let settings = CardinalitySettings {
+            default_limit: Some(1),
+            limits: HashMap::new(),
+        };
+        let metrics = metrics.with_limiter(Arc::new(CardinalityLimiter::new(&settings)));
+        for uid in ["1", "2", "3"] {
+            let tags = Tags::with_tags([("uid".to_owned(), uid.to_owned())].into());
+            metrics.incr_with_tags("login", Some(tags));
+        }
+
+        capture.assert_metric_emitted("login", &[("uid", "1")]);
+        capture.assert_metric_not_emitted("login", &[("uid", "2")]);
+        assert_eq!(
+            capture
+                .find("login")
+                .iter()
+                .filter(|l| l.tags["uid"] == OVERFLOW_VALUE)
+                .count(),
+            2
+        );
+        assert_eq!(capture.find(OVERFLOW_METRIC).len(), 2);
+        capture.assert_metric_emitted(OVERFLOW_METRIC, &[("tag", "uid")]);
+    }
+}
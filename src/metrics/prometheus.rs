@@ -0,0 +1,199 @@
+//! An in-process Prometheus registry fed by the statsd metrics.
+//!
+//! [PrometheusSink] is a cadence `MetricSink` that records each emitted
+//! metric into a shared [PrometheusRegistry], which can then be rendered in
+//! the Prometheus text exposition format for scraping.
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io,
+    sync::{Arc, Mutex},
+};
+
+use cadence::MetricSink;
+
+use super::line::{MetricKind, MetricLine};
+
+/// Prometheus text exposition format content type
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+type Labels = Vec<(String, String)>;
+
+#[derive(Clone, Debug)]
+enum Family {
+    Counter(BTreeMap<Labels, f64>),
+    Gauge(BTreeMap<Labels, f64>),
+    /// Timers, histograms and distributions are reported as summaries
+    /// (a `_sum` and a `_count`) as statsd doesn't specify any buckets.
+    Summary(BTreeMap<Labels, (f64, f64)>),
+}
+
+impl Family {
+    fn new(kind: MetricKind) -> Option<Self> {
+        Some(match kind {
+            MetricKind::Counter | MetricKind::Meter => Family::Counter(BTreeMap::new()),
+            MetricKind::Gauge => Family::Gauge(BTreeMap::new()),
+            MetricKind::Timer | MetricKind::Histogram | MetricKind::Distribution => {
+                Family::Summary(BTreeMap::new())
+            }
+            // Sets can't be represented without tracking every member.
+            MetricKind::Set => return None,
+        })
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Family::Counter(_) => "counter",
+            Family::Gauge(_) => "gauge",
+            Family::Summary(_) => "summary",
+        }
+    }
+}
+
+/// The collection of all metrics recorded since startup.
+#[derive(Debug, Default)]
+pub struct PrometheusRegistry {
+    families: Mutex<BTreeMap<String, Family>>,
+}
+
+impl PrometheusRegistry {
+    /// Record a parsed statsd metric.
+    pub fn record(&self, metric: &MetricLine) {
+        let Some(new_family) = Family::new(metric.kind) else {
+            return;
+        };
+        let labels: Labels = metric
+            .tags
+            .iter()
+            .map(|(k, v)| (sanitize(k), v.clone()))
+            .collect();
+        let mut families = self.families.lock().expect("Prometheus registry poisoned");
+        let family = families.entry(sanitize(&metric.name)).or_insert(new_family);
+        // Account for any client side sampling.
+        let scale = 1.0 / metric.sample_rate.unwrap_or(1.0);
+        match family {
+            Family::Counter(series) => {
+                *series.entry(labels).or_default() += metric.value * scale;
+            }
+            Family::Gauge(series) => {
+                series.insert(labels, metric.value);
+            }
+            Family::Summary(series) => {
+                let (sum, count) = series.entry(labels).or_default();
+                *sum += metric.value * scale;
+                *count += scale;
+            }
+        }
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let families = self.families.lock().expect("Prometheus registry poisoned");
+        let mut out = String::new();
+        for (name, family) in families.iter() {
+            let _ = writeln!(out, "# TYPE {} {}", name, family.type_name());
+            match family {
+                Family::Counter(series) | Family::Gauge(series) => {
+                    for (labels, value) in series {
+                        let _ = writeln!(out, "{}{} {}", name, format_labels(labels), value);
+                    }
+                }
+                Family::Summary(series) => {
+                    for (labels, (sum, count)) in series {
+                        let labels = format_labels(labels);
+                        let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+                        let _ = writeln!(out, "{}_count{} {}", name, labels, count);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// A cadence `MetricSink` recording into a [PrometheusRegistry]
+#[derive(Clone, Debug)]
+pub struct PrometheusSink {
+    registry: Arc<PrometheusRegistry>,
+}
+
+impl PrometheusSink {
+    pub fn new(registry: Arc<PrometheusRegistry>) -> Self {
+        Self { registry }
+    }
+}
+
+impl MetricSink for PrometheusSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let line = metric
+            .parse::<MetricLine>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.registry.record(&line);
+        Ok(metric.len())
+    }
+}
+
+/// Convert a statsd name (e.g. `ua.os.family`) to a valid Prometheus
+/// metric or label name (`ua_os_family`).
+fn sanitize(name: &str) -> String {
+    let mut result: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if result.starts_with(|c: char| c.is_ascii_digit()) {
+        result.insert(0, '_');
+    }
+    result
+}
+
+fn format_labels(labels: &Labels) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", k, v)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cadence::{prelude::*, StatsdClient};
+
+    #[test]
+    fn render_exposition() {
+        let registry = Arc::new(PrometheusRegistry::default());
+        let client = StatsdClient::from_sink("skeleton", PrometheusSink::new(registry.clone()));
+        client
+            .incr_with_tags("req")
+            .with_tag("ua.os.family", "Linux")
+            .send();
+        client
+            .incr_with_tags("req")
+            .with_tag("ua.os.family", "Linux")
+            .send();
+        client
+            .count_with_tags("req", 5)
+            .with_tag("ua.os.family", "Windows")
+            .send();
+        client.gauge("pool.size", 3).unwrap();
+        client.gauge("pool.size", 7).unwrap();
+        client.time("db.query", 10).unwrap();
+        client.time("db.query", 30).unwrap();
+
+        let output = registry.render();
+        assert!(output.contains("# TYPE skeleton_req counter\n"));
+        assert!(output.contains("skeleton_req{ua_os_family=\"Linux\"} 2\n"));
+        assert!(output.contains("skeleton_req{ua_os_family=\"Windows\"} 5\n"));
+        assert!(output.contains("# TYPE skeleton_pool_size gauge\nskeleton_pool_size 7\n"));
+        assert!(output.contains("skeleton_db_query_sum 40\nskeleton_db_query_count 2\n"));
+    }
+}
@@ -0,0 +1,448 @@
+//! Additional cadence metric sinks.
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+    net::{TcpStream, ToSocketAddrs, UdpSocket},
+    panic::RefUnwindSafe,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+#[cfg(unix)]
+use cadence::BufferedUnixMetricSink;
+use cadence::{BufferedUdpMetricSink, MetricSink};
+use serde::Deserialize;
+
+use crate::{error::HandlerError, settings::Settings};
+
+pub type BoxedSink = Box<dyn MetricSink + Send + Sync + RefUnwindSafe>;
+
+/// How long to wait when (re)connecting to a TCP statsd server.
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long to wait before reconnecting after the first failed attempt,
+/// doubling with each further failure up to `TCP_MAX_BACKOFF`.
+const TCP_MIN_BACKOFF: Duration = Duration::from_millis(100);
+const TCP_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Send every metric to each of the contained sinks.
+#[derive(Default)]
+pub struct MultiSink {
+    sinks: Vec<BoxedSink>,
+}
+
+impl MultiSink {
+    pub fn push<T>(&mut self, sink: T)
+    where
+        T: MetricSink + Send + Sync + RefUnwindSafe + 'static,
+    {
+        self.sinks.push(Box::new(sink));
+    }
+}
+
+impl MetricSink for MultiSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let mut written = 0;
+        let mut error = None;
+        for sink in &self.sinks {
+            match sink.emit(metric) {
+                Ok(len) => written = written.max(len),
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(written),
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        for sink in &self.sinks {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// How tags are serialized for the statsd server.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TagFormat {
+    /// `name:1|c|#key:value` (cadence's native format)
+    #[default]
+    Datadog,
+    /// `name,key=value:1|c`
+    Influx,
+    /// `name.key.value:1|c`, with the tags sorted by key so that each series
+    /// has a single path
+    Graphite,
+}
+
+impl TagFormat {
+    /// Convert a DogStatsD formatted metric line to this format.
+    pub fn format<'a>(&self, metric: &'a str) -> Cow<'a, str> {
+        let push_tag: fn(&mut String, &str, &str) = match self {
+            TagFormat::Datadog => return Cow::Borrowed(metric),
+            TagFormat::Influx => |result, key, value| {
+                result.push(',');
+                result.push_str(&influx_escape(key));
+                result.push('=');
+                result.push_str(&influx_escape(value));
+            },
+            TagFormat::Graphite => |result, key, value| {
+                result.push('.');
+                result.push_str(&graphite_escape(key));
+                result.push('.');
+                result.push_str(&graphite_escape(value));
+            },
+        };
+        let Some((name, rest)) = metric.split_once(':') else {
+            return Cow::Borrowed(metric);
+        };
+        let mut tags = vec![];
+        let mut fields = vec![];
+        for field in rest.split('|') {
+            match field.strip_prefix('#') {
+                Some(tag_list) => tags.extend(
+                    tag_list
+                        .split(',')
+                        .filter(|t| !t.is_empty())
+                        .map(|t| t.split_once(':').unwrap_or((t, ""))),
+                ),
+                None => fields.push(field),
+            }
+        }
+        // The tags are in whatever order they were added (e.g. a `HashMap`'s).
+        tags.sort_unstable();
+        let mut result = name.to_owned();
+        for (key, value) in tags {
+            push_tag(&mut result, key, value);
+        }
+        result.push(':');
+        result.push_str(&fields.join("|"));
+        Cow::Owned(result)
+    }
+}
+
+/// Replace the characters Graphite treats specially: dots would create
+/// additional path segments, the others are path separators, whitespace or
+/// glob syntax.
+fn graphite_escape(val: &str) -> String {
+    val.replace(['.', '/', ' ', '{', '}', '*', '[', ']'], "_")
+}
+
+fn influx_escape(val: &str) -> String {
+    val.replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// Rewrite the tags of each metric before passing it to the inner sink.
+pub struct FormattingSink {
+    inner: BoxedSink,
+    format: TagFormat,
+}
+
+impl FormattingSink {
+    pub fn new(inner: BoxedSink, format: TagFormat) -> Self {
+        Self { inner, format }
+    }
+}
+
+impl MetricSink for FormattingSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        self.inner.emit(&self.format.format(metric))
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// How metrics are sent to the statsd server.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsdTransport {
+    #[default]
+    Udp,
+    /// A Unix datagram socket (on Unix only). `statsd_host` is the socket
+    /// path.
+    Uds,
+    /// Newline delimited metrics over a TCP connection.
+    Tcp,
+}
+
+/// Send newline delimited metrics over TCP, reconnecting on failure.
+///
+/// While the server is unreachable, reconnection attempts are spaced out
+/// with an exponential backoff and metrics are dropped in between, rather
+/// than blocking every emit on a connection timeout. (The statsd sink is also
+/// behind a `QueuingMetricSink`, so emitting never blocks the caller.)
+pub struct TcpMetricSink {
+    host: String,
+    connection: Mutex<TcpConnection>,
+}
+
+struct TcpConnection {
+    stream: Option<TcpStream>,
+    /// No connection is attempted before this, after a failure
+    retry_at: Option<Instant>,
+    backoff: Duration,
+}
+
+impl TcpConnection {
+    /// The open connection, connecting if allowed by the backoff.
+    fn get(&mut self, sink: &TcpMetricSink) -> io::Result<&mut TcpStream> {
+        if self.stream.is_none() {
+            if self.retry_at.is_some_and(|at| Instant::now() < at) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "Waiting to reconnect to statsd",
+                ));
+            }
+            match sink.connect() {
+                Ok(stream) => {
+                    self.retry_at = None;
+                    self.backoff = TCP_MIN_BACKOFF;
+                    self.stream = Some(stream);
+                }
+                Err(e) => {
+                    self.retry_at = Some(Instant::now() + self.backoff);
+                    self.backoff = (self.backoff * 2).min(TCP_MAX_BACKOFF);
+                    return Err(e);
+                }
+            }
+        }
+        self.stream
+            .as_mut()
+            .ok_or_else(|| io::Error::other("No statsd connection"))
+    }
+}
+
+impl TcpMetricSink {
+    pub fn new(host: &str) -> Self {
+        Self {
+            host: host.to_owned(),
+            connection: Mutex::new(TcpConnection {
+                stream: None,
+                retry_at: None,
+                backoff: TCP_MIN_BACKOFF,
+            }),
+        }
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut error = io::Error::new(io::ErrorKind::NotFound, "No address for statsd host");
+        for addr in self.host.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, TCP_CONNECT_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+}
+
+impl MetricSink for TcpMetricSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let mut line = metric.as_bytes().to_vec();
+        line.push(b'\n');
+        let mut connection = self
+            .connection
+            .lock()
+            .map_err(|_| io::Error::other("TCP metric sink poisoned"))?;
+        // Retry once on a fresh connection if the current one has failed.
+        for _ in 0..2 {
+            match connection.get(self)?.write_all(&line) {
+                Ok(()) => return Ok(line.len()),
+                Err(e) => {
+                    trace!("⚠️ Metric TCP connection lost: {:?}", e);
+                    connection.stream = None;
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            "Could not send metric over TCP",
+        ))
+    }
+}
+
+/// Build the sink for the statsd server configured in the settings.
+pub fn statsd_sink(opts: &Settings, statsd_host: &str) -> Result<FormattingSink, HandlerError> {
+    let sink: BoxedSink = match opts.statsd_transport {
+        StatsdTransport::Udp => {
+            let socket = UdpSocket::bind("0.0.0.0:0")
+                .map_err(|e| HandlerError::internal(&format!("Could not bind UDP port {:?}", e)))?;
+            socket
+                .set_nonblocking(true)
+                .map_err(|e| HandlerError::internal(&format!("Could not init UDP port {:?}", e)))?;
+
+            let host = (statsd_host, opts.statsd_port);
+            Box::new(BufferedUdpMetricSink::from(host, socket).map_err(|e| {
+                HandlerError::internal(&format!("Could not generate UDP sink {:?}", e))
+            })?)
+        }
+        #[cfg(unix)]
+        StatsdTransport::Uds => {
+            let socket = UnixDatagram::unbound().map_err(|e| {
+                HandlerError::internal(&format!("Could not create Unix socket {:?}", e))
+            })?;
+            socket.set_nonblocking(true).map_err(|e| {
+                HandlerError::internal(&format!("Could not init Unix socket {:?}", e))
+            })?;
+            Box::new(BufferedUnixMetricSink::from(statsd_host, socket))
+        }
+        #[cfg(not(unix))]
+        StatsdTransport::Uds => {
+            return Err(HandlerError::internal(
+                "The uds statsd transport is only supported on Unix",
+            ))
+        }
+        StatsdTransport::Tcp => Box::new(TcpMetricSink::new(&format!(
+            "{}:{}",
+            statsd_host, opts.statsd_port
+        ))),
+    };
+    Ok(FormattingSink::new(sink, opts.statsd_tag_format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        path::PathBuf,
+    };
+
+    const METRIC: &str = "skeleton.req:1|c|@0.5|#ua.os.family:Linux,uri.method:GET";
+
+    #[test]
+    fn tag_formats() {
+        assert_eq!(TagFormat::Datadog.format(METRIC), METRIC);
+        assert_eq!(
+            TagFormat::Influx.format(METRIC),
+            "skeleton.req,ua.os.family=Linux,uri.method=GET:1|c|@0.5"
+        );
+        assert_eq!(
+            TagFormat::Graphite.format(METRIC),
+            "skeleton.req.ua_os_family.Linux.uri_method.GET:1|c|@0.5"
+        );
+        assert_eq!(TagFormat::Influx.format("foo:1|g"), "foo:1|g");
+    }
+
+    #[test]
+    fn graphite_paths_are_stable() {
+        let a = TagFormat::Graphite.format("req:1|c|#uri.route:/item/{id},code:200,ua:a b*[1]");
+        let b = TagFormat::Graphite.format("req:1|c|#ua:a b*[1],code:200,uri.route:/item/{id}");
+        assert_eq!(a, "req.code.200.ua.a_b__1_.uri_route._item__id_:1|c");
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn udp_transport() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let settings = Settings {
+            statsd_port: listener.local_addr().unwrap().port(),
+            statsd_tag_format: TagFormat::Influx,
+            ..Settings::default()
+        };
+        let sink = statsd_sink(&settings, "127.0.0.1").unwrap();
+        sink.emit(METRIC).unwrap();
+        sink.flush().unwrap();
+
+        let mut buf = [0; 512];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf[..len]).unwrap().trim_end(),
+            "skeleton.req,ua.os.family=Linux,uri.method=GET:1|c|@0.5"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn uds_transport() {
+        let path: PathBuf =
+            std::env::temp_dir().join(format!("skeleton-statsd-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixDatagram::bind(&path).unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let settings = Settings {
+            statsd_transport: StatsdTransport::Uds,
+            ..Settings::default()
+        };
+        let sink = statsd_sink(&settings, path.to_str().unwrap()).unwrap();
+        sink.emit(METRIC).unwrap();
+        sink.flush().unwrap();
+
+        let mut buf = [0; 512];
+        let len = listener.recv(&mut buf).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(std::str::from_utf8(&buf[..len]).unwrap().trim_end(), METRIC);
+    }
+
+    #[test]
+    fn tcp_transport_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let settings = Settings {
+            statsd_port: listener.local_addr().unwrap().port(),
+            statsd_transport: StatsdTransport::Tcp,
+            statsd_tag_format: TagFormat::Graphite,
+            ..Settings::default()
+        };
+        let sink = statsd_sink(&settings, "127.0.0.1").unwrap();
+
+        sink.emit("first:1|c").unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(&conn).read_line(&mut line).unwrap();
+        assert_eq!(line, "first:1|c\n");
+
+        // Drop the server side of the connection; the sink should reconnect.
+        conn.shutdown(std::net::Shutdown::Both).unwrap();
+        drop(conn);
+        let mut sent = false;
+        for _ in 0..10 {
+            // The first write(s) after the peer closes may still succeed.
+            sink.emit("second:1|c|#a:b").unwrap();
+            listener.set_nonblocking(true).unwrap();
+            if let Ok((conn, _)) = listener.accept() {
+                conn.set_nonblocking(false).unwrap();
+                let mut line = String::new();
+                BufReader::new(&conn).read_line(&mut line).unwrap();
+                assert_eq!(line, "second.a.b:1|c\n");
+                sent = true;
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(sent, "Sink did not reconnect");
+    }
+
+    #[test]
+    fn tcp_transport_backs_off() {
+        // Nothing is listening on the port once the listener is dropped.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let sink = TcpMetricSink::new(&format!("127.0.0.1:{}", port));
+        let err = sink.emit("first:1|c").unwrap_err();
+        assert_ne!(err.kind(), io::ErrorKind::WouldBlock);
+        // Dropped without another connection attempt until the backoff ends.
+        let err = sink.emit("second:1|c").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        let connection = sink.connection.lock().unwrap();
+        assert_eq!(connection.backoff, TCP_MIN_BACKOFF * 2);
+        assert!(connection.retry_at.unwrap() > Instant::now() - TCP_MIN_BACKOFF);
+    }
+}
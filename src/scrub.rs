@@ -0,0 +1,224 @@
+//! Remove personal data and secrets from the values reported to Sentry and
+//! the logs.
+//!
+//! Values are scrubbed by key (e.g. an `authorization` header is always
+//! dropped), by query parameter name, and by regex rules matching things like
+//! emails or uids embedded in a path.
+use std::{borrow::Cow, collections::HashSet};
+
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+
+use crate::error::HandlerError;
+
+/// Replaces any scrubbed (part of a) value
+pub const FILTERED: &str = "[Filtered]";
+
+lazy_static! {
+    /// A scrubber using the default settings, for `Tags` built without the
+    /// app's state.
+    pub static ref DEFAULT_SCRUBBER: Scrubber =
+        Scrubber::new(&ScrubSettings::default()).expect("Invalid default scrub rules");
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ScrubSettings {
+    /// Set to `false` to report values unmodified
+    pub enabled: bool,
+    /// Keys (e.g. header names) whose values are always removed. These
+    /// include the client's address and its precise location, while the
+    /// `geo.country` tag is coarse enough to be kept.
+    pub keys: Vec<String>,
+    /// Query parameters whose values are removed, from any value
+    pub query_params: Vec<String>,
+    /// Regexes removed from any value. If the regex has a capture group only
+    /// the first group is removed, e.g. `/(\d{4,})(/|$)` keeps the slashes.
+    pub patterns: Vec<String>,
+}
+
+impl Default for ScrubSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            keys: [
+                "authorization",
+                "cookie",
+                "set-cookie",
+                "x-api-key",
+                "client.ip",
+                "geo.subdivision",
+                "geo.city",
+            ]
+            .iter()
+            .map(|k| k.to_string())
+            .collect(),
+            query_params: [
+                "access_token",
+                "code",
+                "email",
+                "key",
+                "password",
+                "secret",
+                "sig",
+                "signature",
+                "token",
+            ]
+            .iter()
+            .map(|p| p.to_string())
+            .collect(),
+            patterns: [
+                // Email addresses
+                r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}",
+                // Bearer/Hawk etc. credentials
+                r"(?i)\b(?:bearer|hawk|basic)\s+([^\s,]+)",
+                // UUIDs
+                r"\b[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}\b",
+                // Numeric path segments, e.g. Sync's uid in `/1.5/{uid}/`
+                // (matching only up to the word boundary, so that the next
+                // segment's `/` is left for it to match)
+                r"/(\d{4,})\b",
+            ]
+            .iter()
+            .map(|p| p.to_string())
+            .collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Scrubber {
+    enabled: bool,
+    keys: HashSet<String>,
+    rules: Vec<Regex>,
+}
+
+impl Scrubber {
+    pub fn new(settings: &ScrubSettings) -> Result<Self, HandlerError> {
+        let mut rules = vec![];
+        if !settings.query_params.is_empty() {
+            let names: Vec<String> = settings
+                .query_params
+                .iter()
+                .map(|p| regex::escape(p))
+                .collect();
+            rules.push(format!(r"(?i)[?&](?:{})=([^&#\s]*)", names.join("|")));
+        }
+        rules.extend(settings.patterns.iter().cloned());
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                Regex::new(rule).map_err(|e| {
+                    HandlerError::internal(&format!("Invalid scrub pattern {:?}: {:?}", rule, e))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            enabled: settings.enabled,
+            keys: settings.keys.iter().map(|k| k.to_lowercase()).collect(),
+            rules,
+        })
+    }
+
+    /// Return the value with any sensitive data replaced by [FILTERED].
+    pub fn scrub<'a>(&self, key: &str, value: &'a str) -> Cow<'a, str> {
+        if !self.enabled {
+            return Cow::Borrowed(value);
+        }
+        if self.keys.contains(&key.to_lowercase()) {
+            return Cow::Borrowed(FILTERED);
+        }
+        let mut result = Cow::Borrowed(value);
+        for rule in &self.rules {
+            if let Cow::Owned(scrubbed) = rule.replace_all(&result, |caps: &Captures<'_>| {
+                let matched = caps.get(0).expect("Missing regex match");
+                match caps.get(1) {
+                    Some(group) => {
+                        let start = group.start() - matched.start();
+                        let end = group.end() - matched.start();
+                        let matched = matched.as_str();
+                        format!("{}{}{}", &matched[..start], FILTERED, &matched[end..])
+                    }
+                    None => FILTERED.to_owned(),
+                }
+            }) {
+                result = Cow::Owned(scrubbed);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_rules() {
+        let scrubber = Scrubber::new(&ScrubSettings::default()).unwrap();
+        assert_eq!(
+            scrubber.scrub(
+                "uri.path",
+                "/1.5/123456/storage/bookmarks?full=1&Token=abc.def"
+            ),
+            "/1.5/[Filtered]/storage/bookmarks?full=1&Token=[Filtered]"
+        );
+        assert_eq!(
+            scrubber.scrub("uri.path", "/v1/accounts/jane.doe+test@example.com/devices"),
+            "/v1/accounts/[Filtered]/devices"
+        );
+        assert_eq!(
+            scrubber.scrub(
+                "msg",
+                "Bearer eyJhbGciOi.eyJzdWIiOi.sig, user 8b2f6a6e-4b8a-4c3c-9f0a-2c1e5d7b9a10"
+            ),
+            "Bearer [Filtered], user [Filtered]"
+        );
+        assert_eq!(
+            scrubber.scrub("uri.path", "/v1/12345/67890/items/98765?sort=1"),
+            "/v1/[Filtered]/[Filtered]/items/[Filtered]?sort=1"
+        );
+        assert_eq!(scrubber.scrub("Authorization", "anything"), FILTERED);
+        assert_eq!(scrubber.scrub("client.ip", "192.0.2.1"), FILTERED);
+        // Short numbers (e.g. API versions) are left alone.
+        assert_eq!(
+            scrubber.scrub("uri.path", "/v1/items/42?keyboard=us"),
+            "/v1/items/42?keyboard=us"
+        );
+        assert_eq!(scrubber.scrub("uri.path", "/v1/1234abcd"), "/v1/1234abcd");
+        assert!(matches!(
+            scrubber.scrub("ua", "Firefox/125.0"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn configured_rules() {
+        let settings = ScrubSettings {
+            keys: vec!["X-Client-Secret".to_owned()],
+            query_params: vec!["state".to_owned()],
+            patterns: vec![r"sk_live_\w+".to_owned()],
+            ..Default::default()
+        };
+        let scrubber = Scrubber::new(&settings).unwrap();
+        assert_eq!(scrubber.scrub("x-client-secret", "abc"), FILTERED);
+        assert_eq!(
+            scrubber.scrub("uri.path", "/cb?state=xyz&token=abc sk_live_123"),
+            "/cb?state=[Filtered]&token=abc [Filtered]"
+        );
+
+        let disabled = Scrubber::new(&ScrubSettings {
+            enabled: false,
+            ..settings
+        })
+        .unwrap();
+        assert_eq!(disabled.scrub("x-client-secret", "abc"), "abc");
+
+        assert!(Scrubber::new(&ScrubSettings {
+            patterns: vec!["(".to_owned()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -2,22 +2,36 @@
 use std::thread;
 
 use actix_web::http::StatusCode;
-use actix_web::web::{self, Data, Json};
+use actix_web::web::{self, Data};
 use actix_web::HttpResponse;
 use serde_json::json;
 
-use crate::settings::Settings;
+use crate::{metrics::prometheus, server::ServerState};
 
 /// Heartbeat is called regularly to access the system state. This call should return quickly
 /// but can be used to do a health check for required systems.
-pub async fn heartbeat(_state: Data<Settings>) -> Json<serde_json::Value> {
-    //TODO: query local state and report results
-    Json(json!({
-        "status": "OK",
+///
+/// All registered `HealthCheck`s are run concurrently. A failing critical check
+/// will cause this to return a `503`.
+pub async fn heartbeat(state: Data<ServerState>) -> HttpResponse {
+    let report = state.health_checks.run().await;
+    HttpResponse::build(report.status.http_status()).json(json!({
+        "status": report.status,
         "version": env!("CARGO_PKG_VERSION"),
+        "checks": report.checks,
     }))
 }
 
+/// Expose the in-process metrics in the Prometheus text exposition format.
+pub async fn metrics(state: Data<ServerState>) -> HttpResponse {
+    match state.prometheus.as_ref() {
+        Some(registry) => HttpResponse::Ok()
+            .content_type(prometheus::CONTENT_TYPE)
+            .body(registry.render()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
 /// Used by the load balancer to indicate the server can respond to
 /// requests. Should just return OK.
 pub async fn lbheartbeat() -> HttpResponse {
@@ -57,5 +71,6 @@ pub fn configure(config: &mut web::ServiceConfig) {
         .service(web::resource("__lbheartbeat__").route(web::get().to(lbheartbeat)))
         .service(web::resource("__heartbeat__").route(web::get().to(heartbeat)))
         .service(web::resource("__version__").route(web::get().to(version)))
+        .service(web::resource("__metrics__").route(web::get().to(metrics)))
         .service(web::resource("__error__").route(web::get().to(test_error)));
 }
@@ -0,0 +1,213 @@
+//! Health checks reported by the `__heartbeat__` Dockerflow endpoint.
+//!
+//! Subsystems (databases, upstream services, caches, etc.) implement
+//! [HealthCheck] and register themselves with the [HealthChecks] registry
+//! held by the `ServerState`.
+use std::{collections::BTreeMap, fmt, sync::Arc, time::Duration};
+
+use actix_web::http::StatusCode;
+use futures::future::{join_all, BoxFuture};
+use serde::Serialize;
+
+/// How long a check may take before it is considered failed.
+pub const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A single system check.
+pub trait HealthCheck: Send + Sync {
+    /// A short, unique name used as the key in the heartbeat response.
+    fn name(&self) -> &str;
+
+    /// How long to wait for `check` before reporting it as failed.
+    fn timeout(&self) -> Duration {
+        DEFAULT_CHECK_TIMEOUT
+    }
+
+    /// Critical checks cause the heartbeat to report an `error`, non-critical
+    /// ones only a `warn`.
+    fn is_critical(&self) -> bool {
+        true
+    }
+
+    /// Query the subsystem, returning a description of the problem on failure.
+    fn check(&self) -> BoxFuture<'_, Result<(), String>>;
+}
+
+/// The state of an individual check, or of the system as a whole.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    Warn,
+    Error,
+}
+
+impl HealthStatus {
+    /// Dockerflow expects a 5xx for a failing heartbeat.
+    pub fn http_status(&self) -> StatusCode {
+        match self {
+            HealthStatus::Ok | HealthStatus::Warn => StatusCode::OK,
+            HealthStatus::Error => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CheckResult {
+    pub status: HealthStatus,
+    pub critical: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    pub duration_ms: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub checks: BTreeMap<String, CheckResult>,
+}
+
+/// The collection of registered checks.
+#[derive(Clone, Default)]
+pub struct HealthChecks {
+    checks: Vec<Arc<dyn HealthCheck>>,
+}
+
+impl fmt::Debug for HealthChecks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.checks.iter().map(|c| c.name()))
+            .finish()
+    }
+}
+
+impl HealthChecks {
+    pub fn register<T: HealthCheck + 'static>(&mut self, check: T) {
+        self.checks.push(Arc::new(check));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.checks.is_empty()
+    }
+
+    /// Run all of the registered checks concurrently.
+    pub async fn run(&self) -> HealthReport {
+        let results = join_all(self.checks.iter().map(|check| async move {
+            let start = std::time::Instant::now();
+            let outcome = actix_rt::time::timeout(check.timeout(), check.check()).await;
+            let (status, message) = match outcome {
+                Ok(Ok(())) => (HealthStatus::Ok, None),
+                Ok(Err(msg)) => (failed_status(check.as_ref()), Some(msg)),
+                Err(_) => (
+                    failed_status(check.as_ref()),
+                    Some(format!("Timed out after {:?}", check.timeout())),
+                ),
+            };
+            (
+                check.name().to_owned(),
+                CheckResult {
+                    status,
+                    critical: check.is_critical(),
+                    message,
+                    duration_ms: start.elapsed().as_millis() as u64,
+                },
+            )
+        }))
+        .await;
+
+        let status = results
+            .iter()
+            .map(|(_, result)| result.status)
+            .max()
+            .unwrap_or(HealthStatus::Ok);
+        HealthReport {
+            status,
+            checks: results.into_iter().collect(),
+        }
+    }
+}
+
+fn failed_status(check: &dyn HealthCheck) -> HealthStatus {
+    if check.is_critical() {
+        HealthStatus::Error
+    } else {
+        HealthStatus::Warn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    struct TestCheck {
+        name: &'static str,
+        critical: bool,
+        result: Result<(), String>,
+        delay: Option<Duration>,
+    }
+
+    impl HealthCheck for TestCheck {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn timeout(&self) -> Duration {
+            Duration::from_millis(50)
+        }
+
+        fn is_critical(&self) -> bool {
+            self.critical
+        }
+
+        fn check(&self) -> BoxFuture<'_, Result<(), String>> {
+            async move {
+                if let Some(delay) = self.delay {
+                    actix_rt::time::sleep(delay).await;
+                }
+                self.result.clone()
+            }
+            .boxed()
+        }
+    }
+
+    fn check(name: &'static str, critical: bool, result: Result<(), String>) -> TestCheck {
+        TestCheck {
+            name,
+            critical,
+            result,
+            delay: None,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn no_checks_is_ok() {
+        let report = HealthChecks::default().run().await;
+        assert_eq!(report.status, HealthStatus::Ok);
+        assert_eq!(report.status.http_status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn non_critical_failure_warns() {
+        let mut checks = HealthChecks::default();
+        checks.register(check("db", true, Ok(())));
+        checks.register(check("cache", false, Err("gone".to_owned())));
+        let report = checks.run().await;
+        assert_eq!(report.status, HealthStatus::Warn);
+        assert_eq!(report.status.http_status(), StatusCode::OK);
+        assert_eq!(report.checks["cache"].message.as_deref(), Some("gone"));
+    }
+
+    #[actix_rt::test]
+    async fn critical_timeout_errors() {
+        let mut checks = HealthChecks::default();
+        checks.register(check("cache", false, Err("gone".to_owned())));
+        checks.register(TestCheck {
+            delay: Some(Duration::from_secs(1)),
+            ..check("db", true, Ok(()))
+        });
+        let report = checks.run().await;
+        assert_eq!(report.status, HealthStatus::Error);
+        assert_eq!(report.status.http_status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report.checks["db"].status, HealthStatus::Error);
+    }
+}
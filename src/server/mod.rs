@@ -1,15 +1,28 @@
 //! Main application server
-use std::sync::Arc;
+use std::{future::IntoFuture, io, sync::Arc, time::Duration};
 
-use actix_cors::Cors;
-use actix_web::{dev, http::StatusCode, middleware::ErrorHandlers, web, App, HttpServer};
+use actix_web::{dev, http::header::HeaderName, web::Data, HttpServer};
 use cadence::StatsdClient;
+use futures::future::{FutureExt, LocalBoxFuture};
 
-use crate::server::dockerflow::configure;
-use crate::web::middleware::sentry::SentryWrapper;
-use crate::{error::HandlerError, metrics, settings::Settings};
+use crate::{
+    error::{ErrorFormat, HandlerError, HandlerResult},
+    logging,
+    metrics::{
+        self, capture::MetricsCapture, cardinality::CardinalityLimiter,
+        prometheus::PrometheusRegistry,
+    },
+    scrub::Scrubber,
+    settings::Settings,
+    tags::UserAgentParser,
+    web::{client_ip::ClientIpResolver, geoip::GeoIp, middleware::access_log::AccessLogSettings},
+};
 
-mod dockerflow;
+pub mod dockerflow;
+pub mod health;
+pub mod reporting;
+
+use health::HealthChecks;
 
 /// This is the global HTTP state object that will be made available to all
 /// HTTP API calls.
@@ -18,48 +31,206 @@ pub struct ServerState {
     /// Metric reporting
     pub metrics: Arc<StatsdClient>,
     pub port: u16,
+    /// Derives the metric tags for user-agents
+    pub user_agent: Arc<UserAgentParser>,
+    /// The header holding the ID of each request
+    pub request_id_header: HeaderName,
+    /// How error responses are rendered
+    pub error_format: ErrorFormat,
+    /// Which requests are logged by the `AccessLogWrapper`
+    pub access_log: Arc<AccessLogSettings>,
+    /// Where the `AccessLogWrapper` logs them
+    pub access_logger: slog::Logger,
+    /// Resolves the client's address from behind trusted proxies
+    pub client_ip: Arc<ClientIpResolver>,
+    /// Locates the client's address, if a database is configured
+    pub geoip: Option<Arc<GeoIp>>,
+    /// Removes personal data from what's reported to Sentry and the logs
+    pub scrubber: Arc<Scrubber>,
+    /// Limits the distinct values of each metric tag
+    pub cardinality: Arc<CardinalityLimiter>,
+    /// In-process metrics exposed on `__metrics__`, if enabled
+    pub prometheus: Option<Arc<PrometheusRegistry>>,
+    /// Subsystem checks reported by `__heartbeat__`
+    pub health_checks: HealthChecks,
+}
+
+impl ServerState {
+    pub fn from_settings(settings: &Settings) -> HandlerResult<Self> {
+        // Register any subsystem health checks here, e.g.
+        // `health_checks.register(DbCheck::new(pool.clone()));`
+        let health_checks = HealthChecks::default();
+        let prometheus = settings
+            .metrics_backend
+            .prometheus()
+            .then(|| Arc::new(PrometheusRegistry::default()));
+        let metrics = Arc::new(metrics::metrics_from_opts(settings, prometheus.clone())?);
+        Ok(ServerState {
+            user_agent: Arc::new(UserAgentParser::new(&settings.user_agent)),
+            metrics,
+            request_id_header: HeaderName::try_from(settings.request_id_header.as_str()).map_err(
+                |e| {
+                    HandlerError::internal(&format!(
+                        "Invalid request_id_header {:?}: {:?}",
+                        settings.request_id_header, e
+                    ))
+                },
+            )?,
+            error_format: settings.error_format,
+            access_log: Arc::new(settings.access_log.clone()),
+            access_logger: logging::access_logger(),
+            client_ip: Arc::new(ClientIpResolver::new(
+                &settings.trusted_proxies,
+                settings.forwarded_header,
+            )?),
+            geoip: GeoIp::from_settings(&settings.geoip)?.map(GeoIp::start),
+            scrubber: Arc::new(Scrubber::new(&settings.scrub)?),
+            port: settings.port,
+            cardinality: Arc::new(CardinalityLimiter::new(&settings.metrics_cardinality)),
+            prometheus,
+            health_checks,
+        })
+    }
+
+    /// Build a state recording all metrics into the returned `MetricsCapture`
+    /// (in addition to any configured backends), for use in tests.
+    pub fn capturing(settings: &Settings) -> HandlerResult<(Self, MetricsCapture)> {
+        let mut state = Self::from_settings(settings)?;
+        let capture = MetricsCapture::with_prefix(&settings.statsd_label);
+        let mut sink = metrics::configured_sink(settings, state.prometheus.clone())?;
+        sink.push(capture.clone());
+        state.metrics = Arc::new(metrics::metrics_with_sink(settings, sink));
+        Ok((state, capture))
+    }
 }
 
-pub struct Server;
+/// The running HTTP server, along with the services it reports to.
+///
+/// Await it to run the server until it is stopped.
+pub struct Server {
+    server: dev::Server,
+    sentry: sentry::ClientInitGuard,
+}
 
+/// Build the actix `App` for the given `Data<ServerState>`. This can also be
+/// used with `actix_web::test::init_service` to test the full application.
 #[macro_export]
 macro_rules! build_app {
     ($state: expr) => {
         // If you want to customize how sentry handles data or reports errors, you're
         // going to need to do some leg work here.
-        App::new()
+        ::actix_web::App::new()
             .app_data($state)
             // Middleware is applied LIFO
             // These will wrap all outbound responses with matching status codes.
-            .wrap(ErrorHandlers::new().handler(StatusCode::NOT_FOUND, HandlerError::render_404))
+            .wrap(
+                ::actix_web::middleware::ErrorHandlers::new()
+                    .handler(
+                        ::actix_web::http::StatusCode::NOT_FOUND,
+                        $crate::error::HandlerError::render_404,
+                    )
+                    // Render our errors in the configured `error_format`.
+                    .default_handler($crate::error::HandlerError::render_error),
+            )
             // These are our wrappers
-            .wrap(SentryWrapper::default())
-            // or use the default sentry wrapper
-            //  .wrap(sentry_actix::Sentry::builder().capture_server_errors(true).finish())
+            // Report errors and trace requests (see `sentry.traces_sample_rate`)
+            .wrap($crate::web::middleware::sentry::SentryWrapper::default())
+            // Record request timing and response status metrics, skipping the
+            // Dockerflow endpoints.
+            .wrap($crate::web::middleware::metrics::MetricsWrapper::default())
+            // Log a `request.summary` for each request, except the
+            // Dockerflow endpoints by default.
+            .wrap($crate::web::middleware::access_log::AccessLogWrapper)
+            // Identify the request, for everything above and the response.
+            .wrap($crate::web::middleware::request_id::RequestIdWrapper)
             // Followed by the "official middleware" so they run first.
             // actix is getting increasingly tighter about CORS headers. Our server is
             // not a huge risk but does deliver XHR JSON content.
             // For now, let's be permissive and use NGINX (the wrapping server)
             // for finer grained specification.
-            .wrap(Cors::permissive())
-            .service(web::scope("").configure(configure))
+            .wrap(::actix_cors::Cors::permissive())
+            .service(::actix_web::web::scope("").configure($crate::server::dockerflow::configure))
     };
 }
 
+/// How often the user-agent cache's hits and misses are reported.
+const UA_CACHE_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
 impl Server {
-    pub async fn with_settings(settings: Settings) -> Result<dev::Server, HandlerError> {
-        let state = ServerState {
-            metrics: Arc::new(metrics::metrics_from_opts(&settings)?),
-            port: settings.port,
-        };
+    pub async fn with_settings(settings: Settings) -> Result<Self, HandlerError> {
+        let state = Data::new(ServerState::from_settings(&settings)?);
+        let sentry = reporting::init(&settings.sentry, metrics::Metrics::from(&state))?;
+        let reporting_state = state.clone();
+        actix_rt::spawn(async move {
+            let metrics = metrics::Metrics::from(&reporting_state);
+            let mut interval = actix_rt::time::interval(UA_CACHE_REPORT_INTERVAL);
+            // The first tick completes immediately.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                reporting_state.user_agent.report_cache_stats(&metrics);
+            }
+        });
         let mut server = HttpServer::new(move || build_app!(state.clone()));
         if let Some(keep_alive) = settings.actix_keep_alive {
-            server = server.keep_alive(std::time::Duration::from_secs(keep_alive));
+            server = server.keep_alive(Duration::from_secs(keep_alive));
         }
         let server = server
             .bind((settings.host, settings.port))
             .expect("Could not get Server in Server::with_settings")
             .run();
-        Ok(server)
+        Ok(Self { server, sentry })
+    }
+
+    pub fn handle(&self) -> dev::ServerHandle {
+        self.server.handle()
+    }
+}
+
+impl IntoFuture for Server {
+    type Output = io::Result<()>;
+    type IntoFuture = LocalBoxFuture<'static, Self::Output>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        async move {
+            let result = self.server.await;
+            // Flush any pending events now that the server has stopped.
+            drop(self.sentry);
+            result
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        http::{header, StatusCode},
+        test::{call_service, init_service, TestRequest},
+    };
+
+    #[actix_rt::test]
+    async fn app_emits_metrics() {
+        let (state, capture) = ServerState::capturing(&Settings::default()).unwrap();
+        let app = init_service(build_app!(Data::new(state))).await;
+
+        let req = TestRequest::get()
+            .uri("/nope")
+            .insert_header((
+                header::USER_AGENT,
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:72.0) Gecko/20100101 Firefox/72.0",
+            ))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let req = TestRequest::get().uri("/__lbheartbeat__").to_request();
+        call_service(&app, req).await;
+
+        capture.assert_metric_emitted(
+            "http.server.response",
+            &[("http.status", "4xx"), ("ua.browser.family", "Firefox")],
+        );
+        assert_eq!(capture.find("http.server.response").len(), 1);
     }
 }
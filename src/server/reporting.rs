@@ -0,0 +1,376 @@
+//! Sentry error reporting configuration.
+//!
+//! Events pass through an [EventFilter] before being sent, which groups
+//! them by errno and route, and drops noisy ones (counting them in the
+//! `sentry.event.dropped` metric instead).
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fs,
+    num::NonZeroUsize,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+use sentry::{protocol::Event, types::Dsn, ClientInitGuard, ClientOptions};
+use serde::Deserialize;
+
+use crate::{error::HandlerError, logging::LogLevel, metrics::Metrics, tags::Tags};
+
+/// Counter incremented for each event not sent, tagged with the `reason`
+pub const DROPPED_EVENT: &str = "sentry.event.dropped";
+/// Event tag holding the `HandlerErrorKind::errno`
+pub const ERRNO_TAG: &str = "errno";
+
+/// The number of distinct events whose rate limit windows are tracked, the
+/// least recently seen being forgotten beyond that.
+const MAX_RATE_LIMITED_EVENTS: NonZeroUsize = NonZeroUsize::new(1000).unwrap();
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SentrySettings {
+    /// Where to send events, defaults to the `SENTRY_DSN` environment
+    /// variable. Sentry is disabled when neither is set.
+    pub dsn: Option<String>,
+    /// Defaults to the `SENTRY_ENVIRONMENT` environment variable
+    pub environment: Option<String>,
+    /// The fraction of error events sent (0.0 - 1.0)
+    pub sample_rate: f32,
+    /// The fraction of transactions sent for performance monitoring (0.0 - 1.0)
+    pub traces_sample_rate: f32,
+    /// Defaults to the host name
+    pub server_name: Option<String>,
+    pub max_breadcrumbs: usize,
+    /// Attach stack traces to messages (errors always include them)
+    pub attach_stacktrace: bool,
+    /// Log the Sentry client's own diagnostics
+    pub debug: bool,
+    /// The Dockerflow `version.json` the release is read from, falling back to
+    /// the crate version (with a warning) when it's missing
+    pub version_file: String,
+    /// Errnos never sent (e.g. those of expected upstream failures)
+    pub drop_errnos: Vec<u16>,
+    /// The number of identical events sent per `rate_limit_interval`
+    /// (0 is unlimited)
+    pub rate_limit: u32,
+    /// In seconds
+    pub rate_limit_interval: u64,
+    /// Log records at or above this level are sent as events (`off` by
+    /// default, `HandlerError`s are already reported by the `SentryWrapper`)
+    pub log_event_level: LogLevel,
+    /// Log records at or above this level (and below `log_event_level`) are
+    /// recorded as breadcrumbs (`off` by default)
+    pub log_breadcrumb_level: LogLevel,
+}
+
+impl Default for SentrySettings {
+    fn default() -> Self {
+        let defaults = ClientOptions::default();
+        Self {
+            dsn: None,
+            environment: None,
+            sample_rate: defaults.sample_rate,
+            traces_sample_rate: defaults.traces_sample_rate,
+            server_name: None,
+            max_breadcrumbs: defaults.max_breadcrumbs,
+            attach_stacktrace: defaults.attach_stacktrace,
+            debug: false,
+            version_file: "/app/version.json".to_owned(),
+            drop_errnos: vec![],
+            rate_limit: 10,
+            rate_limit_interval: 60,
+            log_event_level: LogLevel::Off,
+            log_breadcrumb_level: LogLevel::Off,
+        }
+    }
+}
+
+impl SentrySettings {
+    pub fn client_options(&self) -> Result<ClientOptions, HandlerError> {
+        let dsn = self
+            .dsn
+            .as_deref()
+            .map(Dsn::from_str)
+            .transpose()
+            .map_err(|e| HandlerError::internal(&format!("Invalid Sentry DSN: {:?}", e)))?;
+        let mut options = ClientOptions {
+            dsn,
+            release: Some(self.release()),
+            environment: self.environment.clone().map(Cow::Owned),
+            sample_rate: self.sample_rate,
+            traces_sample_rate: self.traces_sample_rate,
+            max_breadcrumbs: self.max_breadcrumbs,
+            attach_stacktrace: self.attach_stacktrace,
+            debug: self.debug,
+            ..ClientOptions::default()
+        };
+        if let Some(server_name) = self.server_name.as_ref() {
+            options.server_name = Some(Cow::Owned(server_name.clone()));
+        }
+        Ok(options)
+    }
+
+    /// The release of the running code, e.g. `skeleton@1.2.3`.
+    fn release(&self) -> Cow<'static, str> {
+        #[derive(Deserialize)]
+        struct Version {
+            version: String,
+        }
+
+        let contents = fs::read_to_string(&self.version_file)
+            .inspect_err(|e| {
+                warn!(
+                    "⚠️ Could not read the version file {:?}: {}",
+                    self.version_file, e
+                )
+            })
+            .ok();
+        match contents.and_then(|contents| serde_json::from_str::<Version>(&contents).ok()) {
+            // The checked in placeholder isn't a release.
+            Some(Version { version }) if !version.is_empty() && version != "TBD" => {
+                Cow::Owned(format!("{}@{}", env!("CARGO_PKG_NAME"), version))
+            }
+            _ => sentry::release_name!().unwrap_or(Cow::Borrowed(env!("CARGO_PKG_NAME"))),
+        }
+    }
+}
+
+/// Initialize Sentry, filtering events with an [EventFilter]. Reporting stops
+/// when the returned guard is dropped.
+pub fn init(settings: &SentrySettings, metrics: Metrics) -> Result<ClientInitGuard, HandlerError> {
+    let filter = EventFilter::new(settings, metrics);
+    Ok(sentry::init(filter.apply(settings.client_options()?)))
+}
+
+/// The `before_send` pipeline applied to every event.
+pub struct EventFilter {
+    drop_errnos: HashSet<String>,
+    rate_limit: u32,
+    rate_limit_interval: Duration,
+    /// The start of the current window and the number of events sent in it
+    windows: Mutex<LruCache<String, (Instant, u32)>>,
+    metrics: Metrics,
+}
+
+impl EventFilter {
+    pub fn new(settings: &SentrySettings, metrics: Metrics) -> Self {
+        Self {
+            drop_errnos: settings.drop_errnos.iter().map(|e| e.to_string()).collect(),
+            rate_limit: settings.rate_limit,
+            rate_limit_interval: Duration::from_secs(settings.rate_limit_interval),
+            windows: Mutex::new(LruCache::new(MAX_RATE_LIMITED_EVENTS)),
+            metrics,
+        }
+    }
+
+    /// Set the `before_send` callback of the options to this filter.
+    pub fn apply(self, options: ClientOptions) -> ClientOptions {
+        let filter = Arc::new(self);
+        ClientOptions {
+            before_send: Some(Arc::new(move |event| filter.filter(event))),
+            ..options
+        }
+    }
+
+    /// Return the event to send, if any.
+    pub fn filter(&self, mut event: Event<'static>) -> Option<Event<'static>> {
+        let errno = event.tags.get(ERRNO_TAG).cloned();
+        if let Some(errno) = errno.as_ref() {
+            if self.drop_errnos.contains(errno) {
+                self.dropped("errno");
+                return None;
+            }
+            // Group by the type of error and where it happened, rather than
+            // the (often variable) message.
+            let route = event
+                .tags
+                .get("uri.route")
+                .cloned()
+                .unwrap_or_else(|| "unknown".to_owned());
+            event.fingerprint = Cow::Owned(vec![Cow::Owned(errno.clone()), Cow::Owned(route)]);
+        }
+        if !self.within_rate_limit(&event_key(&event, errno.is_some())) {
+            self.dropped("rate_limit");
+            return None;
+        }
+        Some(event)
+    }
+
+    fn within_rate_limit(&self, key: &str) -> bool {
+        if self.rate_limit == 0 {
+            return true;
+        }
+        let mut windows = self.windows.lock().expect("Sentry rate limit poisoned");
+        let now = Instant::now();
+        let (start, count) = windows.get_or_insert_mut(key.to_owned(), || (now, 0));
+        if now.duration_since(*start) >= self.rate_limit_interval {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= self.rate_limit
+    }
+
+    fn dropped(&self, reason: &str) {
+        trace!("Sentry: Dropping event: {}", reason);
+        self.metrics.incr_with_tags(
+            DROPPED_EVENT,
+            Some(Tags::with_tags(
+                [("reason".to_owned(), reason.to_owned())].into(),
+            )),
+        );
+    }
+}
+
+/// What makes events "identical" for rate limiting: their fingerprint when
+/// set from the errno, otherwise their error or message.
+fn event_key(event: &Event<'static>, fingerprinted: bool) -> String {
+    if fingerprinted {
+        return event.fingerprint.join("/");
+    }
+    match event.exception.last() {
+        Some(exc) => format!("{}: {}", exc.ty, exc.value.as_deref().unwrap_or_default()),
+        None => event.message.clone().unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sentry::Level;
+
+    #[test]
+    fn client_options() {
+        let path =
+            std::env::temp_dir().join(format!("skeleton-version-{}.json", std::process::id()));
+        fs::write(&path, r#"{"version": "1.2.3", "commit": "abc"}"#).unwrap();
+        let settings = SentrySettings {
+            dsn: Some("https://public@sentry.example.com/1".to_owned()),
+            environment: Some("stage".to_owned()),
+            sample_rate: 0.5,
+            server_name: Some("web-1".to_owned()),
+            version_file: path.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        let options = settings.client_options().unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(options.dsn.unwrap().host(), "sentry.example.com");
+        assert_eq!(options.environment.as_deref(), Some("stage"));
+        assert_eq!(options.sample_rate, 0.5);
+        assert_eq!(options.server_name.as_deref(), Some("web-1"));
+        assert_eq!(options.release.as_deref(), Some("skeleton@1.2.3"));
+
+        // The placeholder version.json, or a missing one, falls back to the
+        // crate version.
+        let placeholder = SentrySettings {
+            version_file: concat!(env!("CARGO_MANIFEST_DIR"), "/version.json").to_owned(),
+            ..Default::default()
+        };
+        for settings in [placeholder, SentrySettings::default()] {
+            let options = settings.client_options().unwrap();
+            assert_eq!(
+                options.release.as_deref(),
+                Some(concat!("skeleton@", env!("CARGO_PKG_VERSION")))
+            );
+            assert!(options.dsn.is_none());
+        }
+
+        let invalid = SentrySettings {
+            dsn: Some("not a dsn".to_owned()),
+            ..Default::default()
+        };
+        assert!(invalid.client_options().is_err());
+    }
+
+    fn event(errno: Option<&str>, route: &str, message: &str) -> Event<'static> {
+        let mut event = Event {
+            message: Some(message.to_owned()),
+            level: Level::Error,
+            ..Default::default()
+        };
+        if let Some(errno) = errno {
+            event.tags.insert(ERRNO_TAG.to_owned(), errno.to_owned());
+        }
+        event.tags.insert("uri.route".to_owned(), route.to_owned());
+        event
+    }
+
+    #[test]
+    fn bounded_rate_limits() {
+        let settings = SentrySettings {
+            rate_limit: 1,
+            ..Default::default()
+        };
+        let filter = EventFilter::new(&settings, Metrics::noop());
+        let extra = 10;
+        for i in 0..MAX_RATE_LIMITED_EVENTS.get() + extra {
+            assert!(filter.within_rate_limit(&format!("event {}", i)));
+        }
+        let windows = filter.windows.lock().unwrap();
+        assert_eq!(windows.len(), MAX_RATE_LIMITED_EVENTS.get());
+        assert!(!windows.contains("event 0"));
+        assert!(windows.contains(&format!(
+            "event {}",
+            MAX_RATE_LIMITED_EVENTS.get() + extra - 1
+        )));
+    }
+
+    #[test]
+    fn filter_events() {
+        let (metrics, capture) = Metrics::capturing();
+        let settings = SentrySettings {
+            drop_errnos: vec![503],
+            rate_limit: 2,
+            ..Default::default()
+        };
+        let options = EventFilter::new(&settings, metrics).apply(ClientOptions::default());
+        let events = sentry::test::with_captured_events_options(
+            || {
+                sentry::capture_event(event(Some("503"), "/a", "upstream down"));
+                // Only the first 2 of the errno 500s on `/a` are sent,
+                // despite their different messages.
+                for i in 0..4 {
+                    sentry::capture_event(event(Some("500"), "/a", &format!("oops {}", i)));
+                }
+                sentry::capture_event(event(Some("500"), "/b", "oops"));
+                sentry::capture_event(event(None, "/a", "hello"));
+                sentry::capture_event(event(None, "/a", "hello"));
+                sentry::capture_event(event(None, "/a", "hello"));
+            },
+            options,
+        );
+
+        let sent: Vec<(Vec<String>, Option<String>)> = events
+            .iter()
+            .map(|e| {
+                (
+                    e.fingerprint.iter().map(|f| f.to_string()).collect(),
+                    e.message.clone(),
+                )
+            })
+            .collect();
+        let default = vec!["{{ default }}".to_owned()];
+        let fingerprint = |errno: &str, route: &str| vec![errno.to_owned(), route.to_owned()];
+        assert_eq!(
+            sent,
+            vec![
+                (fingerprint("500", "/a"), Some("oops 0".to_owned())),
+                (fingerprint("500", "/a"), Some("oops 1".to_owned())),
+                (fingerprint("500", "/b"), Some("oops".to_owned())),
+                (default.clone(), Some("hello".to_owned())),
+                (default, Some("hello".to_owned())),
+            ]
+        );
+        capture.assert_metric_emitted(DROPPED_EVENT, &[("reason", "errno")]);
+        assert_eq!(
+            capture
+                .find(DROPPED_EVENT)
+                .iter()
+                .filter(|m| m.tags.get("reason").map(String::as_str) == Some("rate_limit"))
+                .count(),
+            3
+        );
+    }
+}
@@ -1,8 +1,27 @@
 //! Application settings objects and initialization
 
+use std::collections::HashMap;
+
 use config::{Config, ConfigError, Environment, File};
 use serde::Deserialize;
 
+use crate::{
+    error::ErrorFormat,
+    metrics::{
+        cardinality::CardinalitySettings,
+        sink::{StatsdTransport, TagFormat},
+        MetricsBackend,
+    },
+    scrub::ScrubSettings,
+    server::reporting::SentrySettings,
+    tags::UserAgentSettings,
+    web::{
+        client_ip::ForwardedHeader,
+        geoip::GeoIpSettings,
+        middleware::{access_log::AccessLogSettings, request_id::REQUEST_ID_HEADER},
+    },
+};
+
 static DEFAULT_PORT: u16 = 8000;
 
 /*
@@ -21,9 +40,42 @@ pub struct Settings {
     pub host: String,
     pub human_logs: bool,
     pub statsd_label: String,
+    /// The statsd server host name, or socket path for the `uds` transport
     pub statsd_host: Option<String>,
     pub statsd_port: u16,
+    /// How metrics are sent: `udp`, `uds` or `tcp`
+    pub statsd_transport: StatsdTransport,
+    /// How tags are serialized: `datadog`, `influx` or `graphite`
+    pub statsd_tag_format: TagFormat,
+    /// Send metrics to `statsd`, a `prometheus` registry, or `both`
+    pub metrics_backend: MetricsBackend,
+    /// Tags added to every metric
+    pub metrics_tags: MetricsTagSettings,
+    /// Limits on the distinct values of each metric tag
+    pub metrics_cardinality: CardinalitySettings,
+    /// Which user-agent attributes are reported as tags
+    pub user_agent: UserAgentSettings,
+    /// Addresses or CIDRs (e.g. `10.0.0.0/8`) of the proxies/load balancers
+    /// whose `forwarded_header` is trusted
+    pub trusted_proxies: Vec<String>,
+    /// The header the trusted proxies write: `x-forwarded-for` or
+    /// `forwarded` (the other is ignored)
+    pub forwarded_header: ForwardedHeader,
+    /// The header holding the ID of each request, as set by the load
+    /// balancer (an ID is generated when it's missing)
+    pub request_id_header: String,
+    /// Tag requests with the client's location, e.g.
+    /// `SKELETON__GEOIP__DATABASE=/data/GeoLite2-City.mmdb`
+    pub geoip: GeoIpSettings,
+    /// Rules for removing personal data from Sentry events and logs
+    pub scrub: ScrubSettings,
+    /// Sentry error reporting
+    pub sentry: SentrySettings,
+    /// The `request.summary` record logged for each request
+    pub access_log: AccessLogSettings,
     pub actix_keep_alive: Option<u64>,
+    /// How error responses are rendered: `errno`, `json` or `problem`
+    pub error_format: ErrorFormat,
 }
 
 impl Default for Settings {
@@ -36,8 +88,57 @@ impl Default for Settings {
             statsd_label: PREFIX.to_owned(),
             statsd_host: None,
             statsd_port: 8125,
+            statsd_transport: StatsdTransport::default(),
+            statsd_tag_format: TagFormat::default(),
+            metrics_backend: MetricsBackend::default(),
+            metrics_tags: MetricsTagSettings::default(),
+            metrics_cardinality: CardinalitySettings::default(),
+            user_agent: UserAgentSettings::default(),
+            trusted_proxies: Vec::new(),
+            forwarded_header: ForwardedHeader::default(),
+            request_id_header: REQUEST_ID_HEADER.to_owned(),
+            geoip: GeoIpSettings::default(),
+            scrub: ScrubSettings::default(),
+            sentry: SentrySettings::default(),
+            access_log: AccessLogSettings::default(),
             actix_keep_alive: None,
+            error_format: ErrorFormat::default(),
+        }
+    }
+}
+
+/// Constant tags applied to every metric, e.g.
+/// `SKELETON__METRICS_TAGS__ENVIRONMENT=stage` or
+/// `SKELETON__METRICS_TAGS__EXTRA__REGION=us-west-1`
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct MetricsTagSettings {
+    /// Include the crate version as `version` (adds a series per release)
+    pub version: bool,
+    /// Include the host name as `hostname`
+    pub hostname: bool,
+    /// The deployment environment (e.g. `prod`), included as `environment`
+    pub environment: Option<String>,
+    /// Any additional key/values
+    pub extra: HashMap<String, String>,
+}
+
+impl MetricsTagSettings {
+    /// The resolved set of tags.
+    pub fn tags(&self) -> HashMap<String, String> {
+        let mut tags = self.extra.clone();
+        if self.version {
+            tags.insert("version".to_owned(), env!("CARGO_PKG_VERSION").to_owned());
+        }
+        if self.hostname {
+            if let Some(hostname) = hostname::get().ok().and_then(|h| h.into_string().ok()) {
+                tags.insert("hostname".to_owned(), hostname);
+            }
+        }
+        if let Some(environment) = self.environment.as_ref() {
+            tags.insert("environment".to_owned(), environment.clone());
         }
+        tags
     }
 }
 
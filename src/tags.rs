@@ -1,24 +1,48 @@
-use std::collections::{BTreeMap, HashMap};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
 
 use actix_web::{
     dev::{Payload, RequestHead},
-    http::header::USER_AGENT,
+    http::header::{HeaderMap, USER_AGENT},
+    web::Data,
     Error, FromRequest, HttpMessage, HttpRequest,
 };
 use futures::future;
 use futures::future::Ready;
+use lazy_static::lazy_static;
+use lru::LruCache;
+use regex::Regex;
 use serde::{
     ser::{SerializeMap, Serializer},
-    Serialize,
+    Deserialize, Serialize,
 };
 use serde_json::value::Value;
 use slog::{Key, Record, KV};
 use woothee::parser::{Parser, WootheeResult};
 
+use crate::{
+    metrics::Metrics,
+    scrub::{Scrubber, DEFAULT_SCRUBBER},
+    server::ServerState,
+    web::{
+        client_ip::ClientIpResolver,
+        geoip::GeoIp,
+        middleware::request_id::{RequestId, REQUEST_ID_KEY},
+    },
+};
+
 // List of valid user-agent attributes to keep, anything not in this
 // list is considered 'Other'. We log the user-agent on connect always
 // to retain the full string, but for DD more tags are expensive so we
-// limit to these.
+// limit to these. These are the defaults for `UserAgentSettings`.
 const VALID_UA_BROWSER: &[&str] = &["Chrome", "Firefox", "Safari", "Opera"];
 
 // See dataset.rs in https://github.com/woothee/woothee-rust for the
@@ -26,9 +50,272 @@ const VALID_UA_BROWSER: &[&str] = &["Chrome", "Firefox", "Safari", "Opera"];
 // field). Windows has many values and we only care that its Windows
 const VALID_UA_OS: &[&str] = &["Firefox OS", "Linux", "Mac OSX"];
 
-pub fn parse_user_agent(agent: &str) -> (WootheeResult<'_>, &str, &str) {
-    let parser = Parser::new();
-    let wresult = parser.parse(agent).unwrap_or_else(|| WootheeResult {
+// woothee's `category`s reported for `ua.device`
+const VALID_UA_DEVICE: &[&str] = &["pc", "smartphone", "mobilephone", "crawler"];
+
+lazy_static! {
+    static ref DEFAULT_UA_PARSER: UserAgentParser = UserAgentParser::default();
+    static ref DEFAULT_CLIENT_IP: ClientIpResolver = ClientIpResolver::default();
+    static ref WOOTHEE: Parser = Parser::new();
+    // Pre-release Firefox versions, e.g. `Firefox/124.0a1` or `Firefox/123.0b9`
+    static ref FIREFOX_VERSION: Regex = Regex::new(r"Firefox/\d+\.\d+(a\d|b\d*)?").unwrap();
+}
+
+// User-Agent Client Hints, which Chromium based browsers send in place of
+// the (frozen) details of their user-agent string.
+const SEC_CH_UA: &str = "sec-ch-ua";
+const SEC_CH_UA_MOBILE: &str = "sec-ch-ua-mobile";
+const SEC_CH_UA_PLATFORM: &str = "sec-ch-ua-platform";
+
+/// Tag holding the matched route pattern, see [Tags::add_route]
+pub const URI_ROUTE: &str = "uri.route";
+
+/// Which user-agent attributes are reported as tags.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct UserAgentSettings {
+    /// The number of distinct user-agents to cache the tags of (0 disables)
+    pub cache_size: usize,
+    /// Browser families reported as `ua.browser.family`, others are `Other`
+    pub browsers: Vec<String>,
+    /// OS families reported as `ua.os.family`, others are `Other`
+    pub os: Vec<String>,
+    /// Add `ua.device` (pc/smartphone/mobilephone/crawler/other)
+    pub device_category: bool,
+    /// Only report the major version in `ua.browser.ver` (e.g. `72`)
+    pub major_version: bool,
+    /// Add `ua.firefox.channel` (nightly/beta/release) for Firefox
+    pub firefox_channel: bool,
+}
+
+impl Default for UserAgentSettings {
+    fn default() -> Self {
+        Self {
+            cache_size: 1000,
+            browsers: VALID_UA_BROWSER.iter().map(|b| b.to_string()).collect(),
+            os: VALID_UA_OS.iter().map(|o| o.to_string()).collect(),
+            device_category: false,
+            major_version: false,
+            firefox_channel: false,
+        }
+    }
+}
+
+type UaTags = Arc<HashMap<String, String>>;
+
+/// Metric counting the user-agents whose tags were found in the cache
+pub const UA_CACHE_HIT: &str = "ua.cache.hit";
+/// Metric counting the user-agents that had to be parsed
+pub const UA_CACHE_MISS: &str = "ua.cache.miss";
+
+/// Derives the metric tags for a user-agent string.
+///
+/// Clients send a relatively small number of distinct user-agents, so the
+/// derived tags are cached rather than re-parsing every request. The cache's
+/// hits and misses are counted, to be reported periodically (see
+/// [UserAgentParser::report_cache_stats]) rather than on every lookup.
+pub struct UserAgentParser {
+    settings: UserAgentSettings,
+    cache: Option<Mutex<LruCache<String, UaTags>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Default for UserAgentParser {
+    fn default() -> Self {
+        Self::new(&UserAgentSettings::default())
+    }
+}
+
+impl fmt::Debug for UserAgentParser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserAgentParser")
+            .field("settings", &self.settings)
+            .finish()
+    }
+}
+
+impl UserAgentParser {
+    pub fn new(settings: &UserAgentSettings) -> Self {
+        Self {
+            settings: settings.clone(),
+            cache: NonZeroUsize::new(settings.cache_size)
+                .map(|size| Mutex::new(LruCache::new(size))),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Send the cache hits and misses counted since the last report.
+    pub fn report_cache_stats(&self, metrics: &Metrics) {
+        if self.cache.is_none() {
+            return;
+        }
+        let hits = self.hits.swap(0, Ordering::Relaxed);
+        let misses = self.misses.swap(0, Ordering::Relaxed);
+        metrics.count(UA_CACHE_HIT, hits as i64);
+        metrics.count(UA_CACHE_MISS, misses as i64);
+    }
+
+    /// Determine a base os/browser for metrics' tags
+    fn families<'a>(&self, wresult: &WootheeResult<'a>) -> (&'a str, &'a str) {
+        let metrics_os = if wresult.os.starts_with("Windows") {
+            "Windows"
+        } else if self.settings.os.iter().any(|os| os == wresult.os) {
+            wresult.os
+        } else {
+            "Other"
+        };
+        let metrics_browser = if self.settings.browsers.iter().any(|b| b == wresult.name) {
+            wresult.name
+        } else {
+            "Other"
+        };
+        (metrics_os, metrics_browser)
+    }
+
+    /// Return the tags derived from the user-agent.
+    pub fn tags(&self, agent: &str) -> UaTags {
+        let Some(cache) = self.cache.as_ref() else {
+            return Arc::new(self.parse(agent));
+        };
+        if let Some(tags) = cache.lock().expect("UA cache poisoned").get(agent) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return tags.clone();
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        // Parse outside of the lock, there's no harm in occasionally parsing
+        // the same new user-agent concurrently.
+        let tags = Arc::new(self.parse(agent));
+        cache
+            .lock()
+            .expect("UA cache poisoned")
+            .put(agent.to_owned(), tags.clone());
+        tags
+    }
+
+    fn parse(&self, agent: &str) -> HashMap<String, String> {
+        let mut tags = HashMap::new();
+        let wresult = woothee_parse(agent);
+        let (metrics_os, metrics_browser) = self.families(&wresult);
+        insert_if_not_empty("ua.os.family", metrics_os, &mut tags);
+        insert_if_not_empty("ua.browser.family", metrics_browser, &mut tags);
+        insert_if_not_empty("ua.name", wresult.name, &mut tags);
+        insert_if_not_empty("ua.os.ver", &wresult.os_version.clone(), &mut tags);
+        let version = if self.settings.major_version {
+            wresult.version.split('.').next().unwrap_or_default()
+        } else {
+            wresult.version
+        };
+        insert_if_not_empty("ua.browser.ver", version, &mut tags);
+        if self.settings.device_category {
+            let device = if VALID_UA_DEVICE.contains(&wresult.category) {
+                wresult.category
+            } else {
+                "other"
+            };
+            insert_if_not_empty("ua.device", device, &mut tags);
+        }
+        if self.settings.firefox_channel && wresult.name == "Firefox" {
+            insert_if_not_empty("ua.firefox.channel", firefox_channel(agent), &mut tags);
+        }
+        tags
+    }
+
+    /// Return the tags derived from any User-Agent Client Hints, which take
+    /// precedence over those derived from the user-agent string.
+    pub fn client_hint_tags(&self, headers: &HeaderMap) -> HashMap<String, String> {
+        let mut tags = HashMap::new();
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        if let Some((brand, version)) = header(SEC_CH_UA).and_then(hinted_brand) {
+            let family = match brand {
+                "Google Chrome" | "Chromium" => "Chrome",
+                "Microsoft Edge" => "Edge",
+                "Opera" | "Opera GX" => "Opera",
+                other => other,
+            };
+            let family = if self.settings.browsers.iter().any(|b| b == family) {
+                family
+            } else {
+                "Other"
+            };
+            // The hints are sent by the client, so only allowlisted names and
+            // a numeric major version are reported.
+            insert_if_not_empty("ua.browser.family", family, &mut tags);
+            insert_if_not_empty("ua.name", family, &mut tags);
+            let major = version.split('.').next().unwrap_or_default();
+            if major.len() <= 4 && major.bytes().all(|b| b.is_ascii_digit()) {
+                insert_if_not_empty("ua.browser.ver", major, &mut tags);
+            }
+        }
+        if let Some(platform) = header(SEC_CH_UA_PLATFORM).map(|p| p.trim().trim_matches('"')) {
+            // Match woothee's names for the allowlist.
+            let os = match platform {
+                "macOS" => "Mac OSX",
+                "Chrome OS" => "ChromeOS",
+                other => other,
+            };
+            let os = if os == "Windows" || self.settings.os.iter().any(|o| o == os) {
+                os
+            } else {
+                "Other"
+            };
+            insert_if_not_empty("ua.os.family", os, &mut tags);
+        }
+        if self.settings.device_category {
+            match header(SEC_CH_UA_MOBILE).map(str::trim) {
+                Some("?1") => insert_if_not_empty("ua.device", "smartphone", &mut tags),
+                Some("?0") => insert_if_not_empty("ua.device", "pc", &mut tags),
+                _ => {}
+            }
+        }
+        tags
+    }
+}
+
+/// Pick the brand and version reported in a `Sec-CH-UA` list, e.g.
+/// `"Chromium";v="124", "Google Chrome";v="124", "Not-A.Brand";v="99"`.
+///
+/// The list contains "GREASE" brands to discourage naive parsing, and the
+/// Chromium brand alongside the actual browser's, which is preferred.
+fn hinted_brand(header: &str) -> Option<(&str, &str)> {
+    let brands: Vec<(&str, &str)> = header
+        .split(',')
+        .filter_map(|entry| {
+            let (brand, params) = entry.split_once(';').unwrap_or((entry, ""));
+            let brand = brand.trim().trim_matches('"');
+            let version = params
+                .split(';')
+                .filter_map(|p| p.trim().strip_prefix("v="))
+                .map(|v| v.trim_matches('"'))
+                .next()
+                .unwrap_or_default();
+            let grease = brand.starts_with("Not") || brand.contains("Brand");
+            (!brand.is_empty() && !grease).then_some((brand, version))
+        })
+        .collect();
+    brands
+        .iter()
+        .find(|(brand, _)| *brand != "Chromium")
+        .or_else(|| brands.first())
+        .copied()
+}
+
+/// Guess the Firefox release channel from the version suffix.
+fn firefox_channel(agent: &str) -> &'static str {
+    match FIREFOX_VERSION
+        .captures(agent)
+        .and_then(|c| c.get(1))
+        .map(|m| m.as_str())
+    {
+        Some(suffix) if suffix.starts_with('a') => "nightly",
+        Some(_) => "beta",
+        None => "release",
+    }
+}
+
+fn woothee_parse(agent: &str) -> WootheeResult<'_> {
+    WOOTHEE.parse(agent).unwrap_or_else(|| WootheeResult {
         name: "",
         category: "",
         os: "",
@@ -36,28 +323,22 @@ pub fn parse_user_agent(agent: &str) -> (WootheeResult<'_>, &str, &str) {
         browser_type: "",
         version: "",
         vendor: "",
-    });
-
-    // Determine a base os/browser for metrics' tags
-    let metrics_os = if wresult.os.starts_with("Windows") {
-        "Windows"
-    } else if VALID_UA_OS.contains(&wresult.os) {
-        wresult.os
-    } else {
-        "Other"
-    };
-    let metrics_browser = if VALID_UA_BROWSER.contains(&wresult.name) {
-        wresult.name
-    } else {
-        "Other"
-    };
-    (wresult, metrics_os, metrics_browser)
+    })
+}
+
+/// The metrics' tags (`ua.os.family`, `ua.browser.family`, etc.) of the
+/// user-agent, using the default (cached) `UserAgentParser`.
+pub fn parse_user_agent(agent: &str) -> UaTags {
+    DEFAULT_UA_PARSER.tags(agent)
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct Tags {
     pub tags: HashMap<String, String>,
     pub extra: HashMap<String, String>,
+    /// Scrubs the values reported to Sentry and the logs (the default rules
+    /// if not set)
+    pub scrubber: Option<Arc<Scrubber>>,
 }
 
 impl Serialize for Tags {
@@ -66,9 +347,9 @@ impl Serialize for Tags {
         S: Serializer,
     {
         let mut seq = serializer.serialize_map(Some(self.tags.len()))?;
-        for tag in self.tags.clone() {
-            if !tag.1.is_empty() {
-                seq.serialize_entry(&tag.0, &tag.1)?;
+        for (key, val) in &self.tags {
+            if !val.is_empty() {
+                seq.serialize_entry(key, &self.scrub(key, val))?;
             }
         }
         seq.end()
@@ -92,27 +373,106 @@ fn insert_if_not_empty(label: &str, val: &str, tags: &mut HashMap<String, String
 // function requires it, is left as an exercise for the reader.
 impl Tags {
     pub fn from_request_head(req_head: &RequestHead) -> Tags {
+        Self::from_request_head_with(req_head, &DEFAULT_UA_PARSER, &DEFAULT_CLIENT_IP, None)
+    }
+
+    /// Build the tags using the configuration in the app state, if available.
+    pub fn from_request_head_with_state(
+        req_head: &RequestHead,
+        state: Option<&Data<ServerState>>,
+    ) -> Tags {
+        match state {
+            Some(state) => Tags {
+                scrubber: Some(state.scrubber.clone()),
+                ..Self::from_request_head_with(
+                    req_head,
+                    &state.user_agent,
+                    &state.client_ip,
+                    state.geoip.as_deref(),
+                )
+            },
+            None => Self::from_request_head(req_head),
+        }
+    }
+
+    pub fn from_request_head_with(
+        req_head: &RequestHead,
+        parser: &UserAgentParser,
+        client_ip: &ClientIpResolver,
+        geoip: Option<&GeoIp>,
+    ) -> Tags {
         // Return an Option<> type because the later consumers (HandlerErrors) presume that
         // tags are optional and wrapped by an Option<> type.
         let mut tags = HashMap::new();
         let mut extra = HashMap::new();
         if let Some(ua) = req_head.headers().get(USER_AGENT) {
             if let Ok(uas) = ua.to_str() {
-                // if you wanted to parse out the user agent using some out-of-scope user agent parser like woothee
-                let (ua_result, metrics_os, metrics_browser) = parse_user_agent(uas);
-                insert_if_not_empty("ua.os.family", metrics_os, &mut tags);
-                insert_if_not_empty("ua.browser.family", metrics_browser, &mut tags);
-                insert_if_not_empty("ua.name", ua_result.name, &mut tags);
-                insert_if_not_empty("ua.os.ver", &ua_result.os_version.clone(), &mut tags);
-                insert_if_not_empty("ua.browser.ver", ua_result.version, &mut tags);
+                tags.extend(parser.tags(uas).iter().map(|(k, v)| (k.clone(), v.clone())));
                 extra.insert("ua".to_owned(), uas.to_string());
             }
         }
+        tags.extend(parser.client_hint_tags(req_head.headers()));
         tags.insert("uri.method".to_owned(), req_head.method.to_string());
         // `uri.path` causes too much cardinality for influx but keep it in
-        // extra for sentry
+        // extra for sentry (`uri.route` is tagged instead once routed)
         extra.insert("uri.path".to_owned(), req_head.uri.to_string());
-        Tags { tags, extra }
+        // Never a metric tag: it's both high cardinality and personal data.
+        if let Some(ip) = client_ip.resolve(req_head) {
+            extra.insert("client.ip".to_owned(), ip.to_string());
+            if let Some(location) = geoip.and_then(|geoip| geoip.lookup(ip)) {
+                // Only the country is coarse enough for a metric tag.
+                if let Some(country) = location.country {
+                    tags.insert("geo.country".to_owned(), country);
+                }
+                if let Some(subdivision) = location.subdivision {
+                    extra.insert("geo.subdivision".to_owned(), subdivision);
+                }
+                if let Some(city) = location.city {
+                    extra.insert("geo.city".to_owned(), city);
+                }
+            }
+        }
+        Tags {
+            tags,
+            extra,
+            scrubber: None,
+        }
+    }
+
+    /// The request's tags, built once (by whichever middleware or extractor
+    /// asks first) and kept in its extensions for the rest, including the
+    /// `uri.route` and `request_id`.
+    ///
+    /// Tags inserted into the extensions by the app are returned as is.
+    pub fn for_request(req: &HttpRequest) -> Tags {
+        if let Some(tags) = req.extensions().get::<Tags>() {
+            return tags.clone();
+        }
+        let mut tags = Self::from_request_head_with_state(req.head(), req.app_data());
+        tags.add_route(req);
+        tags.add_request_id(req);
+        req.extensions_mut().insert(tags.clone());
+        tags
+    }
+
+    /// Add the `uri.route` tag: the pattern of the matched resource
+    /// (e.g. `/1.5/{uid}/storage/{collection}`), which unlike `uri.path`
+    /// has a bounded cardinality.
+    ///
+    /// Only available once the request has been routed; unmatched requests
+    /// are left untagged.
+    pub fn add_route(&mut self, req: &HttpRequest) {
+        if let Some(route) = req.match_pattern() {
+            self.tags.insert(URI_ROUTE.to_owned(), route);
+        }
+    }
+
+    /// Add the `request_id` assigned by the `RequestIdWrapper`, if any.
+    pub fn add_request_id(&mut self, req: &HttpRequest) {
+        if let Some(request_id) = req.extensions().get::<RequestId>() {
+            self.extra
+                .insert(REQUEST_ID_KEY.to_owned(), request_id.to_string());
+        }
     }
 
     pub fn with_tags(tags: HashMap<String, String>) -> Tags {
@@ -121,7 +481,7 @@ impl Tags {
         }
         Tags {
             tags,
-            extra: HashMap::new(),
+            ..Default::default()
         }
     }
 
@@ -134,20 +494,30 @@ impl Tags {
         self.tags.extend(tags);
     }
 
+    /// Remove any personal data or secrets from the value of `key`.
+    pub fn scrub<'a>(&self, key: &str, val: &'a str) -> Cow<'a, str> {
+        self.scrubber
+            .as_deref()
+            .unwrap_or(&DEFAULT_SCRUBBER)
+            .scrub(key, val)
+    }
+
+    /// The scrubbed tags, for Sentry.
     pub fn tag_tree(self) -> BTreeMap<String, String> {
         let mut result = BTreeMap::new();
 
-        for (k, v) in self.tags {
-            result.insert(k.clone(), v.clone());
+        for (k, v) in &self.tags {
+            result.insert(k.clone(), self.scrub(k, v).into_owned());
         }
         result
     }
 
+    /// The scrubbed extra data, for Sentry.
     pub fn extra_tree(self) -> BTreeMap<String, Value> {
         let mut result = BTreeMap::new();
 
-        for (k, v) in self.extra {
-            result.insert(k.clone(), Value::from(v));
+        for (k, v) in &self.extra {
+            result.insert(k.clone(), Value::from(self.scrub(k, v).into_owned()));
         }
         result
     }
@@ -158,15 +528,7 @@ impl FromRequest for Tags {
     type Future = Ready<Result<Self, Self::Error>>;
 
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        let tags = {
-            let exts = req.extensions();
-            match exts.get::<Tags>() {
-                Some(t) => t.clone(),
-                None => Tags::from_request_head(req.head()),
-            }
-        };
-
-        future::ok(tags)
+        future::ok(Tags::for_request(req))
     }
 }
 
@@ -185,8 +547,230 @@ impl From<Tags> for BTreeMap<String, String> {
 impl KV for Tags {
     fn serialize(&self, _rec: &Record<'_>, serializer: &mut dyn slog::Serializer) -> slog::Result {
         for (key, val) in &self.tags {
-            serializer.emit_str(Key::from(key.clone()), val)?;
+            serializer.emit_str(Key::from(key.clone()), &self.scrub(key, val))?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NIGHTLY_ANDROID: &str =
+        "Mozilla/5.0 (Android 14; Mobile; rv:125.0) Gecko/125.0 Firefox/125.0a1";
+
+    #[test]
+    fn derived_ua_tags_are_opt_in() {
+        let tags = UserAgentParser::default().tags(NIGHTLY_ANDROID);
+        assert!(!tags.is_empty());
+        assert!(!tags.contains_key("ua.device"));
+        assert!(!tags.contains_key("ua.firefox.channel"));
+
+        let parser = UserAgentParser::new(&UserAgentSettings {
+            device_category: true,
+            major_version: true,
+            firefox_channel: true,
+            ..Default::default()
+        });
+        let tags = parser.tags(NIGHTLY_ANDROID);
+        assert_eq!(tags["ua.device"], "smartphone");
+        assert_eq!(tags["ua.browser.ver"], "125");
+        assert_eq!(tags["ua.firefox.channel"], "nightly");
+
+        let tags = parser
+            .tags("Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:72.0) Gecko/20100101 Firefox/72.0");
+        assert_eq!(tags["ua.device"], "pc");
+        assert_eq!(tags["ua.firefox.channel"], "release");
+    }
+
+    #[test]
+    fn configurable_allowlists() {
+        let parser = UserAgentParser::new(&UserAgentSettings {
+            browsers: vec!["Firefox".to_owned()],
+            os: vec!["Android".to_owned()],
+            ..Default::default()
+        });
+        let tags = parser.tags(NIGHTLY_ANDROID);
+        assert_eq!(tags["ua.os.family"], "Android");
+        assert_eq!(tags["ua.browser.family"], "Firefox");
+
+        let tags = parser.tags(
+            "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+        );
+        assert_eq!(tags["ua.os.family"], "Other");
+        assert_eq!(tags["ua.browser.family"], "Other");
+    }
+
+    #[test]
+    fn cached_tags() {
+        let parser = UserAgentParser::new(&UserAgentSettings {
+            cache_size: 1,
+            ..Default::default()
+        });
+        let first = parser.tags(NIGHTLY_ANDROID);
+        let second = parser.tags(NIGHTLY_ANDROID);
+        assert!(Arc::ptr_eq(&first, &second));
+        // Evicted by the next user-agent, so parsed again.
+        parser.tags("curl/8.0");
+        let third = parser.tags(NIGHTLY_ANDROID);
+        assert!(!Arc::ptr_eq(&first, &third));
+        assert_eq!(first, third);
+
+        let tags = parse_user_agent(NIGHTLY_ANDROID);
+        assert!(Arc::ptr_eq(&tags, &parse_user_agent(NIGHTLY_ANDROID)));
+        assert_eq!(tags["ua.browser.family"], "Firefox");
+    }
+
+    #[test]
+    fn cache_stats() {
+        use crate::metrics::capture::MetricsCapture;
+        use cadence::StatsdClient;
+
+        let capture = MetricsCapture::default();
+        let metrics = Metrics::from(StatsdClient::from_sink("", capture.clone()));
+        let parser = UserAgentParser::default();
+        parser.tags(NIGHTLY_ANDROID);
+        parser.tags(NIGHTLY_ANDROID);
+        parser.tags(NIGHTLY_ANDROID);
+        parser.tags("curl/8.0");
+        parser.report_cache_stats(&metrics);
+        assert_eq!(capture.find(UA_CACHE_HIT)[0].value, 2.0);
+        assert_eq!(capture.find(UA_CACHE_MISS)[0].value, 2.0);
+
+        // Only what's been counted since.
+        capture.clear();
+        parser.tags("curl/8.0");
+        parser.report_cache_stats(&metrics);
+        assert_eq!(capture.find(UA_CACHE_HIT)[0].value, 1.0);
+        assert_eq!(capture.find(UA_CACHE_MISS)[0].value, 0.0);
+    }
+
+    #[test]
+    fn client_hints() {
+        use actix_web::test::TestRequest;
+
+        let req = TestRequest::get()
+            .insert_header((
+                USER_AGENT,
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+            ))
+            .insert_header((
+                SEC_CH_UA,
+                "\"Chromium\";v=\"126\", \"Google Chrome\";v=\"126\", \"Not-A.Brand\";v=\"99\"",
+            ))
+            .insert_header((SEC_CH_UA_PLATFORM, "\"Linux\""))
+            .insert_header((SEC_CH_UA_MOBILE, "?0"))
+            .peer_addr("198.51.100.7:1234".parse().unwrap())
+            .to_http_request();
+        let parser = UserAgentParser::new(&UserAgentSettings {
+            device_category: true,
+            ..Default::default()
+        });
+        let tags =
+            Tags::from_request_head_with(req.head(), &parser, &ClientIpResolver::default(), None);
+        assert_eq!(tags.tags["ua.browser.family"], "Chrome");
+        assert_eq!(tags.tags["ua.name"], "Chrome");
+        assert_eq!(tags.tags["ua.browser.ver"], "126");
+        assert_eq!(tags.tags["ua.os.family"], "Linux");
+        assert_eq!(tags.tags["ua.device"], "pc");
+        assert_eq!(tags.extra["client.ip"], "198.51.100.7");
+        assert!(!tags.tags.contains_key("client.ip"));
+
+        assert_eq!(
+            hinted_brand("\"Not)A;Brand\";v=\"8\", \"Chromium\";v=\"138\""),
+            Some(("Chromium", "138"))
+        );
+        assert_eq!(hinted_brand(""), None);
+
+        // Spoofed hints can't add tag values.
+        let req = TestRequest::get()
+            .insert_header((SEC_CH_UA, "\"Google Chrome\";v=\"126 or 1=1\""))
+            .to_http_request();
+        let tags = parser.client_hint_tags(req.headers());
+        assert!(tags
+            .values()
+            .all(|v| ["Chrome", "Other"].contains(&v.as_str())));
+        let req = TestRequest::get()
+            .insert_header((SEC_CH_UA, "\"Brave\";v=\"126.0.1\""))
+            .to_http_request();
+        let tags = parser.client_hint_tags(req.headers());
+        assert_eq!(tags["ua.name"], "Other");
+        assert_eq!(tags["ua.browser.ver"], "126");
+    }
+
+    #[test]
+    fn geo_tags() {
+        use crate::web::geoip::GeoIpSettings;
+
+        let geoip = GeoIp::from_settings(&GeoIpSettings {
+            database: Some(
+                concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/tests/fixtures/GeoIP2-City-Test.mmdb"
+                )
+                .to_owned(),
+            ),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let req = actix_web::test::TestRequest::get()
+            .peer_addr("192.0.2.10:1234".parse().unwrap())
+            .to_http_request();
+        let tags = Tags::from_request_head_with(
+            req.head(),
+            &UserAgentParser::default(),
+            &ClientIpResolver::default(),
+            geoip.as_ref(),
+        );
+        assert_eq!(tags.tags["geo.country"], "CA");
+        assert_eq!(tags.extra["geo.subdivision"], "Ontario");
+        assert_eq!(tags.extra["geo.city"], "Toronto");
+        assert!(!tags.tags.contains_key("geo.city"));
+    }
+
+    #[test]
+    fn scrubbed_output() {
+        let req = actix_web::test::TestRequest::get()
+            .uri("/1.5/123456/storage?email=jane@example.com")
+            .peer_addr("192.0.2.1:1234".parse().unwrap())
+            .insert_header(("Authorization", "Bearer abc"))
+            .to_http_request();
+        let mut tags = Tags::from_request_head(req.head());
+        tags.tags
+            .insert("authorization".to_owned(), "Bearer abc".to_owned());
+
+        let json = serde_json::to_value(&tags).unwrap();
+        assert_eq!(json["authorization"], crate::scrub::FILTERED);
+        let extra = tags.clone().extra_tree();
+        assert_eq!(
+            extra["uri.path"],
+            "/1.5/[Filtered]/storage?email=[Filtered]"
+        );
+        assert_eq!(extra["client.ip"], crate::scrub::FILTERED);
+        assert_eq!(tags.tag_tree()["authorization"], crate::scrub::FILTERED);
+    }
+
+    #[test]
+    fn built_once_per_request() {
+        let req = actix_web::test::TestRequest::get()
+            .insert_header((
+                USER_AGENT,
+                "Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0",
+            ))
+            .to_http_request();
+        req.extensions_mut().insert(RequestId("abc-123".to_owned()));
+        let tags = Tags::for_request(&req);
+        assert_eq!(tags.tags["ua.browser.family"], "Firefox");
+        assert_eq!(tags.extra[REQUEST_ID_KEY], "abc-123");
+
+        // Later callers get the stored tags, including any added to them.
+        req.extensions_mut()
+            .get_mut::<Tags>()
+            .unwrap()
+            .tags
+            .insert("extra".to_owned(), "tag".to_owned());
+        assert_eq!(Tags::for_request(&req).tags["extra"], "tag");
+    }
+}
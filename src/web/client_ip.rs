@@ -0,0 +1,234 @@
+//! Resolve the client's IP address when running behind proxies.
+//!
+//! The header the proxies write (`X-Forwarded-For` or `Forwarded`) is only
+//! believed when the connection comes from a trusted proxy. The chain is then
+//! walked from the nearest hop outwards, skipping trusted proxies, so a client
+//! can't spoof its address by sending the header itself. The other header is
+//! ignored, as the proxies pass it through from the client unchanged.
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::{
+    dev::RequestHead,
+    http::header::{HeaderMap, FORWARDED},
+};
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::error::HandlerError;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// The header the trusted proxies append the client's address to.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For` (e.g. NGINX's `$proxy_add_x_forwarded_for`)
+    #[default]
+    XForwardedFor,
+    /// The RFC 7239 `Forwarded` header
+    Forwarded,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ClientIpResolver {
+    trusted_proxies: Vec<IpNet>,
+    header: ForwardedHeader,
+}
+
+impl ClientIpResolver {
+    /// Trust the given proxies, each either a CIDR (`10.0.0.0/8`) or a
+    /// single address, and the given header they write.
+    pub fn new(trusted_proxies: &[String], header: ForwardedHeader) -> Result<Self, HandlerError> {
+        let trusted_proxies = trusted_proxies
+            .iter()
+            .map(|proxy| {
+                proxy
+                    .parse::<IpNet>()
+                    .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|e| {
+                        HandlerError::internal(&format!(
+                            "Invalid trusted proxy {:?}: {:?}",
+                            proxy, e
+                        ))
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            trusted_proxies,
+            header,
+        })
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    /// The address of the client that made the request, if known.
+    pub fn resolve(&self, req_head: &RequestHead) -> Option<IpAddr> {
+        let peer = req_head.peer_addr.map(|addr| addr.ip());
+        let mut client = peer?;
+        if !self.is_trusted(&client) {
+            return Some(client);
+        }
+        let chain = match self.header {
+            ForwardedHeader::XForwardedFor => x_forwarded_for_chain(req_head.headers()),
+            ForwardedHeader::Forwarded => forwarded_chain(req_head.headers()),
+        };
+        for hop in chain.into_iter().rev() {
+            // Stop at anything we can't interpret (e.g. `for=unknown`),
+            // reporting the last proxy rather than guessing.
+            let Some(ip) = hop else {
+                break;
+            };
+            client = ip;
+            if !self.is_trusted(&client) {
+                break;
+            }
+        }
+        Some(client)
+    }
+}
+
+/// The addresses listed in the `Forwarded` header, ordered from the
+/// originating client to the nearest proxy.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(FORWARDED)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value.trim().trim_matches('"')))
+            })
+        })
+        .collect()
+}
+
+/// The addresses listed in the `X-Forwarded-For` header, ordered from the
+/// originating client to the nearest proxy.
+fn x_forwarded_for_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|node| parse_node(node.trim()))
+        .collect()
+}
+
+/// Parse an address, optionally including a port (`[2001:db8::1]:4711` or
+/// `192.0.2.1:4711`).
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Some(ip) = node
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once(']'))
+        .and_then(|(ip, _)| ip.parse().ok())
+    {
+        return Some(ip);
+    }
+    node.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn resolve(trusted: &[&str], peer: &str, headers: &[(&str, &str)]) -> Option<IpAddr> {
+        resolve_with(ForwardedHeader::XForwardedFor, trusted, peer, headers)
+    }
+
+    fn resolve_with(
+        header: ForwardedHeader,
+        trusted: &[&str],
+        peer: &str,
+        headers: &[(&str, &str)],
+    ) -> Option<IpAddr> {
+        let trusted: Vec<String> = trusted.iter().map(|t| t.to_string()).collect();
+        let resolver = ClientIpResolver::new(&trusted, header).unwrap();
+        let mut req = TestRequest::get().peer_addr(peer.parse().unwrap());
+        for header in headers {
+            req = req.append_header(*header);
+        }
+        resolver.resolve(req.to_http_request().head())
+    }
+
+    fn ip(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let headers = [("X-Forwarded-For", "192.0.2.1")];
+        assert_eq!(
+            resolve(&[], "198.51.100.7:1234", &headers),
+            ip("198.51.100.7")
+        );
+        assert_eq!(
+            resolve(&["10.0.0.0/8"], "198.51.100.7:1234", &headers),
+            ip("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn skips_trusted_proxies() {
+        let trusted = ["10.0.0.0/8", "203.0.113.5"];
+        // The client prepended a spoofed address, which is ignored.
+        let headers = [("X-Forwarded-For", "192.0.2.1, 198.51.100.7, 203.0.113.5")];
+        assert_eq!(
+            resolve(&trusted, "10.1.2.3:1234", &headers),
+            ip("198.51.100.7")
+        );
+        // Every hop is trusted: report the furthest.
+        let headers = [("X-Forwarded-For", "10.9.9.9, 203.0.113.5")];
+        assert_eq!(resolve(&trusted, "10.1.2.3:1234", &headers), ip("10.9.9.9"));
+        assert_eq!(resolve(&trusted, "10.1.2.3:1234", &[]), ip("10.1.2.3"));
+    }
+
+    #[test]
+    fn ignores_spoofed_forwarded() {
+        // NGINX appends the peer to `X-Forwarded-For`, but passes the
+        // client's `Forwarded` header through.
+        let headers = [
+            ("Forwarded", "for=192.0.2.60"),
+            ("X-Forwarded-For", "198.51.100.7"),
+        ];
+        assert_eq!(
+            resolve(&["10.0.0.0/8"], "10.1.2.3:1234", &headers),
+            ip("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn reads_forwarded() {
+        let headers = [
+            (
+                "Forwarded",
+                "for=192.0.2.60;proto=http, For=\"[2001:db8:cafe::17]:4711\"",
+            ),
+            ("X-Forwarded-For", "198.51.100.7"),
+        ];
+        let forwarded = ForwardedHeader::Forwarded;
+        assert_eq!(
+            resolve_with(forwarded, &["10.0.0.0/8"], "10.1.2.3:1234", &headers),
+            ip("2001:db8:cafe::17")
+        );
+        let headers = [("Forwarded", "for=unknown")];
+        assert_eq!(
+            resolve_with(forwarded, &["10.0.0.0/8"], "10.1.2.3:1234", &headers),
+            ip("10.1.2.3")
+        );
+    }
+
+    #[test]
+    fn invalid_proxy() {
+        assert!(
+            ClientIpResolver::new(&["10.0.0.0/33".to_owned()], ForwardedHeader::default()).is_err()
+        );
+    }
+}
@@ -2,10 +2,58 @@
 //!
 //! Handles ensuring the header's, body, and query parameters are correct, extraction to
 //! relevant types, and failing correctly with the appropriate errors if issues arise.
-use actix_web::{dev::Payload, web::Data, Error, FromRequest, HttpRequest};
-use futures::future::{FutureExt, LocalBoxFuture};
+use std::ops::Deref;
 
-use crate::{error::HandlerErrorKind, server::ServerState};
+use actix_web::{dev::Payload, web::Data, Error, FromRequest, HttpMessage, HttpRequest};
+use futures::future::{self, FutureExt, LocalBoxFuture, Ready};
+use slog::slog_o;
+
+use crate::{
+    error::HandlerErrorKind,
+    server::ServerState,
+    tags::Tags,
+    web::middleware::request_id::{RequestId, REQUEST_ID_KEY},
+};
+
+/// A logger for the current request, including its `request_id` and
+/// `Tags` (e.g. `uri.method` and `uri.route`).
+///
+/// The `RequestIdWrapper` also makes it the `slog_scope` logger while the
+/// request is handled, so the global `info!` etc. macros log through it too.
+#[derive(Clone, Debug)]
+pub struct RequestLogger(pub slog::Logger);
+
+impl RequestLogger {
+    pub fn new(req: &HttpRequest) -> Self {
+        Self::with_parent(&slog_scope::logger(), req)
+    }
+
+    /// The request's logger derived from the given one (e.g. the access
+    /// log's).
+    pub fn with_parent(parent: &slog::Logger, req: &HttpRequest) -> Self {
+        let tags = Tags::for_request(req);
+        let request_id = req.extensions().get::<RequestId>().map(|id| id.to_string());
+        Self(parent.new(slog_o!(REQUEST_ID_KEY => request_id, tags)))
+    }
+}
+
+impl Deref for RequestLogger {
+    type Target = slog::Logger;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl FromRequest for RequestLogger {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let logger = req.extensions().get::<RequestLogger>().cloned();
+        future::ok(logger.unwrap_or_else(|| RequestLogger::new(req)))
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct ExampleRequest;
@@ -30,3 +78,73 @@ impl FromRequest for ExampleRequest {
         .boxed_local()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use actix_web::{
+        test::{call_and_read_body_json, init_service, TestRequest},
+        web, App, HttpResponse,
+    };
+    use slog::{Record, KV};
+
+    use crate::{
+        logging::tests::KvCollector, settings::Settings,
+        web::middleware::request_id::RequestIdWrapper,
+    };
+
+    /// The key/values of the logger.
+    fn logger_kv(logger: &slog::Logger) -> HashMap<String, String> {
+        let mut collect = KvCollector::default();
+        let rs = slog::record_static!(slog::Level::Info, "");
+        logger
+            .list()
+            .serialize(
+                &Record::new(&rs, &format_args!(""), slog::b!()),
+                &mut collect,
+            )
+            .unwrap();
+        collect.0
+    }
+
+    async fn handler(logger: RequestLogger) -> HttpResponse {
+        HttpResponse::Ok().json([logger_kv(&logger), logger_kv(&slog_scope::logger())])
+    }
+
+    #[actix_rt::test]
+    async fn request_logger() {
+        let state = ServerState::from_settings(&Settings::default()).unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .wrap(RequestIdWrapper)
+                .route("/item/{id}", web::get().to(handler)),
+        )
+        .await;
+        let req = TestRequest::get()
+            .uri("/item/1")
+            .insert_header(("X-Request-Id", "abc-123"))
+            .insert_header((
+                "User-Agent",
+                "Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0",
+            ))
+            .to_request();
+        let [extracted, scoped]: [HashMap<String, String>; 2] =
+            call_and_read_body_json(&app, req).await;
+        assert_eq!(extracted["request_id"], "abc-123");
+        assert_eq!(extracted["uri.method"], "GET");
+        assert_eq!(extracted["uri.route"], "/item/{id}");
+        assert_eq!(extracted["ua.browser.family"], "Firefox");
+        assert_eq!(extracted, scoped);
+
+        // Without the middleware, the logger is built on demand.
+        let app = init_service(App::new().route("/item/{id}", web::get().to(handler))).await;
+        let req = TestRequest::get().uri("/item/1").to_request();
+        let [extracted, _]: [HashMap<String, String>; 2] = call_and_read_body_json(&app, req).await;
+        assert_eq!(extracted["uri.route"], "/item/{id}");
+        // (Emitted as `null`.)
+        assert_eq!(extracted["request_id"], "");
+    }
+}
@@ -0,0 +1,260 @@
+//! Offline GeoIP lookups of the client's address.
+//!
+//! Uses a local MaxMind (GeoIP2/GeoLite2 City or Country) database, which is
+//! reloaded by a background thread when the file changes so that it can be
+//! updated (e.g. by `geoipupdate`) without restarting the server.
+use std::{
+    fs,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use maxminddb::{geoip2, Reader};
+use serde::Deserialize;
+
+use crate::error::HandlerError;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct GeoIpSettings {
+    /// Path to the `.mmdb` database, GeoIP tagging is disabled if not set
+    pub database: Option<String>,
+    /// Number of seconds between checks for an updated database (0 disables
+    /// reloading)
+    pub reload_interval: u64,
+}
+
+impl Default for GeoIpSettings {
+    fn default() -> Self {
+        Self {
+            database: None,
+            reload_interval: 60,
+        }
+    }
+}
+
+/// The location of an address, as far as the database knows.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Location {
+    /// ISO 3166-1 country code (e.g. `FR`)
+    pub country: Option<String>,
+    /// The largest subdivision (state, province, etc.) containing the city
+    pub subdivision: Option<String>,
+    pub city: Option<String>,
+}
+
+struct Database {
+    reader: Arc<Reader<Vec<u8>>>,
+    modified: Option<SystemTime>,
+}
+
+pub struct GeoIp {
+    path: PathBuf,
+    reload_interval: Duration,
+    db: RwLock<Database>,
+}
+
+impl std::fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GeoIp").field("path", &self.path).finish()
+    }
+}
+
+impl GeoIp {
+    /// Load the configured database, if any.
+    pub fn from_settings(settings: &GeoIpSettings) -> Result<Option<Self>, HandlerError> {
+        let Some(path) = settings.database.as_ref() else {
+            return Ok(None);
+        };
+        let path = PathBuf::from(path);
+        let db = load(&path).map_err(|e| HandlerError::internal(&e))?;
+        Ok(Some(Self {
+            path,
+            reload_interval: Duration::from_secs(settings.reload_interval),
+            db: RwLock::new(db),
+        }))
+    }
+
+    /// Share the database, checking for updates every `reload_interval` on a
+    /// background thread (which stops once the database is dropped).
+    pub fn start(self) -> Arc<Self> {
+        let interval = self.reload_interval;
+        let geoip = Arc::new(self);
+        if interval.is_zero() {
+            return geoip;
+        }
+        let weak = Arc::downgrade(&geoip);
+        let spawned = thread::Builder::new()
+            .name("geoip-reload".to_owned())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                match weak.upgrade() {
+                    Some(geoip) => geoip.reload_if_modified(),
+                    None => break,
+                }
+            });
+        if let Err(e) = spawned {
+            warn!("⚠️ Could not start the GeoIP reload thread: {:?}", e);
+        }
+        geoip
+    }
+
+    /// Replace the database if the file has been modified since it was loaded.
+    ///
+    /// The old database is kept if the new one can't be read.
+    fn reload_if_modified(&self) {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified == self.db.read().expect("GeoIP lock poisoned").modified {
+            return;
+        }
+        match load(&self.path) {
+            Ok(db) => {
+                info!("Reloaded GeoIP database {:?}", self.path);
+                *self.db.write().expect("GeoIP lock poisoned") = db;
+            }
+            Err(e) => warn!("⚠️ {}", e),
+        }
+    }
+
+    /// Look up the location of the given address.
+    pub fn lookup(&self, ip: IpAddr) -> Option<Location> {
+        let reader = self.db.read().expect("GeoIP lock poisoned").reader.clone();
+        let city = match reader
+            .lookup(ip)
+            .and_then(|r| r.decode::<geoip2::City<'_>>())
+        {
+            Ok(city) => city?,
+            Err(e) => {
+                trace!("GeoIP lookup of {} failed: {:?}", ip, e);
+                return None;
+            }
+        };
+        Some(Location {
+            country: city.country.iso_code.map(str::to_owned),
+            subdivision: city
+                .subdivisions
+                .first()
+                .and_then(|s| s.names.english)
+                .map(str::to_owned),
+            city: city.city.names.english.map(str::to_owned),
+        })
+    }
+}
+
+fn load(path: &PathBuf) -> Result<Database, String> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+    let reader = Reader::open_readfile(path)
+        .map_err(|e| format!("Could not load GeoIP database {:?}: {:?}", path, e))?;
+    Ok(Database {
+        reader: Arc::new(reader),
+        modified,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::File, path::Path};
+
+    /// A copy of the fixture, which the test may then modify.
+    fn copy_fixture(name: &str, to: &Path, modified: SystemTime) {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name);
+        fs::copy(fixture, to).unwrap();
+        File::options()
+            .write(true)
+            .open(to)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[test]
+    fn lookup_and_reload() {
+        let path = std::env::temp_dir().join(format!("skeleton-geoip-{}.mmdb", std::process::id()));
+        let epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        copy_fixture("GeoIP2-City-Test.mmdb", &path, epoch);
+        let geoip = GeoIp::from_settings(&GeoIpSettings {
+            database: Some(path.to_string_lossy().into_owned()),
+            reload_interval: 0,
+        })
+        .unwrap()
+        .unwrap();
+
+        let ip: IpAddr = "192.0.2.10".parse().unwrap();
+        assert_eq!(
+            geoip.lookup(ip),
+            Some(Location {
+                country: Some("CA".to_owned()),
+                subdivision: Some("Ontario".to_owned()),
+                city: Some("Toronto".to_owned()),
+            })
+        );
+        assert_eq!(geoip.lookup("198.51.100.1".parse().unwrap()), None);
+        assert_eq!(geoip.lookup("2001:db8::1".parse().unwrap()), None);
+
+        // Unchanged until reloaded.
+        copy_fixture(
+            "GeoIP2-City-Test-Updated.mmdb",
+            &path,
+            epoch + Duration::from_secs(60),
+        );
+        assert_eq!(geoip.lookup(ip).unwrap().country.as_deref(), Some("CA"));
+        geoip.reload_if_modified();
+        assert_eq!(geoip.lookup(ip).unwrap().country.as_deref(), Some("DE"));
+
+        // A broken update keeps the previous database.
+        fs::write(&path, b"garbage").unwrap();
+        geoip.reload_if_modified();
+        assert_eq!(geoip.lookup(ip).unwrap().country.as_deref(), Some("DE"));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reloads_in_the_background() {
+        let path =
+            std::env::temp_dir().join(format!("skeleton-geoip-bg-{}.mmdb", std::process::id()));
+        let epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        copy_fixture("GeoIP2-City-Test.mmdb", &path, epoch);
+        let geoip = GeoIp::from_settings(&GeoIpSettings {
+            database: Some(path.to_string_lossy().into_owned()),
+            reload_interval: 1,
+        })
+        .unwrap()
+        .unwrap()
+        .start();
+        copy_fixture(
+            "GeoIP2-City-Test-Updated.mmdb",
+            &path,
+            epoch + Duration::from_secs(60),
+        );
+
+        let ip: IpAddr = "192.0.2.10".parse().unwrap();
+        let mut country = None;
+        for _ in 0..50 {
+            country = geoip.lookup(ip).and_then(|l| l.country);
+            if country.as_deref() == Some("DE") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        let _ = fs::remove_file(&path);
+        assert_eq!(country.as_deref(), Some("DE"));
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        assert!(GeoIp::from_settings(&GeoIpSettings::default())
+            .unwrap()
+            .is_none());
+        let missing = GeoIpSettings {
+            database: Some("/nonexistent.mmdb".to_owned()),
+            ..Default::default()
+        };
+        assert!(GeoIp::from_settings(&missing).is_err());
+    }
+}
@@ -0,0 +1,318 @@
+//! Log a `request.summary` record for every (sampled) request.
+//!
+//! The record is logged through the access log's own logger (see
+//! `logging::access_logger`), including the `request_id` and `Tags` (method,
+//! route, user agent families, etc.) like the request's `RequestLogger`,
+//! along with the response's status, duration and size.
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header::CONTENT_LENGTH, StatusCode},
+    web::Data,
+    Error,
+};
+use futures::{future::LocalBoxFuture, FutureExt};
+use futures_util::future::{ok, Ready};
+use serde::Deserialize;
+use slog::info;
+
+use crate::{
+    error::HandlerError,
+    logging,
+    server::ServerState,
+    web::{extractors::RequestLogger, DOCKER_FLOW_ENDPOINTS},
+};
+
+/// The message of the access log records
+pub const REQUEST_SUMMARY: &str = "request.summary";
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct AccessLogSettings {
+    pub enabled: bool,
+    /// The fraction of requests logged (0.0 - 1.0)
+    pub sample_rate: f64,
+    /// Paths never logged, defaulting to the Dockerflow endpoints
+    pub exclude: Vec<String>,
+}
+
+impl Default for AccessLogSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            sample_rate: 1.0,
+            exclude: DOCKER_FLOW_ENDPOINTS
+                .iter()
+                .map(|p| p.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl AccessLogSettings {
+    fn should_log(&self, path: &str) -> bool {
+        self.enabled
+            && !self.exclude.iter().any(|p| p == path)
+            && (self.sample_rate >= 1.0 || rand::random::<f64>() < self.sample_rate)
+    }
+}
+
+#[derive(Default)]
+pub struct AccessLogWrapper;
+
+impl<S, B> Transform<S, ServiceRequest> for AccessLogWrapper
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AccessLogWrapperMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AccessLogWrapperMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct AccessLogWrapperMiddleware<S> {
+    service: Rc<RefCell<S>>,
+}
+
+/// What's known of the request once it's been handled.
+struct Summary {
+    logger: RequestLogger,
+    start: Instant,
+    bytes_in: Option<u64>,
+}
+
+impl Summary {
+    fn log(self, status: StatusCode, bytes_out: Option<u64>, errno: Option<i32>) {
+        info!(
+            self.logger,
+            "{}", REQUEST_SUMMARY;
+            "code" => status.as_u16(),
+            "t" => self.start.elapsed().as_millis() as u64,
+            "bytes_in" => self.bytes_in,
+            "bytes_out" => bytes_out,
+            "errno" => errno,
+        );
+    }
+}
+
+fn errno(err: &Error) -> Option<i32> {
+    err.as_error::<HandlerError>()
+        .map(|herr| herr.kind().errno())
+}
+
+impl<S, B> Service<ServiceRequest> for AccessLogWrapperMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, sreq: ServiceRequest) -> Self::Future {
+        let (settings, logger) = match sreq.app_data::<Data<ServerState>>() {
+            Some(state) => (state.access_log.clone(), state.access_logger.clone()),
+            None => (
+                Arc::new(AccessLogSettings::default()),
+                logging::access_logger(),
+            ),
+        };
+        if !settings.should_log(sreq.path()) {
+            return self.service.call(sreq).boxed_local();
+        }
+        let logger = RequestLogger::with_parent(&logger, sreq.request());
+        let summary = Summary {
+            logger,
+            start: Instant::now(),
+            bytes_in: sreq
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|len| len.to_str().ok())
+                .and_then(|len| len.parse().ok()),
+        };
+
+        let fut = self.service.call(sreq);
+
+        async move {
+            match fut.await {
+                Ok(resp) => {
+                    let bytes_out = match resp.response().body().size() {
+                        BodySize::Sized(size) => Some(size),
+                        BodySize::None | BodySize::Stream => None,
+                    };
+                    let errno = resp.response().error().and_then(errno);
+                    summary.log(resp.status(), bytes_out, errno);
+                    Ok(resp)
+                }
+                Err(err) => {
+                    summary.log(err.as_response_error().status_code(), None, errno(&err));
+                    Err(err)
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        http::StatusCode,
+        middleware::ErrorHandlers,
+        test::{call_service, init_service, read_body, TestRequest},
+        web, App, HttpResponse,
+    };
+    use slog::slog_o;
+
+    use crate::{
+        error::{ErrorFormat, HandlerErrorKind, HandlerResult},
+        logging::tests::CaptureDrain,
+        settings::Settings,
+        web::middleware::request_id::RequestIdWrapper,
+    };
+
+    const FIREFOX: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0";
+
+    async fn fail() -> HandlerResult<String> {
+        Err(HandlerErrorKind::Conflict("busy".to_owned()).into())
+    }
+
+    /// The records logged for each request, made through the same middleware
+    /// stack as `build_app!`, along with the size of each response's body.
+    fn records(settings: Settings, paths: &[&str]) -> (CaptureDrain, Vec<usize>) {
+        let drain = CaptureDrain::default();
+        let logger = slog::Logger::root(drain.clone(), slog_o!());
+        // The access logger is the scope's logger when logging isn't set up.
+        let sizes = slog_scope::scope(&logger, || {
+            actix_rt::System::new().block_on(async {
+                let state = ServerState::from_settings(&settings).unwrap();
+                let app = init_service(
+                    App::new()
+                        .app_data(Data::new(state))
+                        .wrap(
+                            ErrorHandlers::new()
+                                .handler(StatusCode::NOT_FOUND, HandlerError::render_404)
+                                .default_handler(HandlerError::render_error),
+                        )
+                        .wrap(AccessLogWrapper)
+                        .wrap(RequestIdWrapper)
+                        .route(
+                            "/item/{id}",
+                            web::post().to(|| async { HttpResponse::Ok().body("hello") }),
+                        )
+                        .route("/fail", web::post().to(fail))
+                        .route("/__lbheartbeat__", web::post().to(HttpResponse::Ok)),
+                )
+                .await;
+                let mut sizes = vec![];
+                for path in paths {
+                    let req = TestRequest::post()
+                        .uri(path)
+                        .insert_header(("X-Request-Id", "abc"))
+                        .insert_header(("User-Agent", FIREFOX))
+                        .insert_header((CONTENT_LENGTH, "4"))
+                        .set_payload("ping")
+                        .to_request();
+                    let resp = call_service(&app, req).await;
+                    sizes.push(read_body(resp).await.len());
+                }
+                sizes
+            })
+        });
+        (drain, sizes)
+    }
+
+    #[test]
+    fn logs_requests() {
+        let (drain, _) = records(
+            Settings::default(),
+            &["/item/1", "/fail", "/missing", "/__lbheartbeat__"],
+        );
+        let records = drain.records();
+        assert_eq!(records.len(), 3);
+        let (msg, kv) = &records[0];
+        assert_eq!(msg, REQUEST_SUMMARY);
+        assert_eq!(kv["request_id"], "abc");
+        assert_eq!(kv["uri.method"], "POST");
+        assert_eq!(kv["uri.route"], "/item/{id}");
+        assert_eq!(kv["ua.browser.family"], "Firefox");
+        assert_eq!(kv["code"], "200");
+        assert_eq!(kv["bytes_in"], "4");
+        assert_eq!(kv["bytes_out"], "5");
+        assert!(kv.contains_key("t"));
+        assert_eq!(kv["errno"], "");
+
+        let (_, kv) = &records[1];
+        assert_eq!(kv["uri.route"], "/fail");
+        assert_eq!(kv["code"], "409");
+        assert_eq!(kv["errno"], "409");
+
+        // Rendered by `HandlerError::render_404`.
+        let (_, kv) = &records[2];
+        assert!(!kv.contains_key("uri.route"));
+        assert_eq!(kv["code"], "404");
+        assert_eq!(kv["errno"], "404");
+    }
+
+    #[test]
+    fn logs_bytes_sent() {
+        let settings = Settings {
+            error_format: ErrorFormat::Json,
+            ..Default::default()
+        };
+        let (drain, sizes) = records(settings, &["/item/1", "/fail", "/missing"]);
+        let records = drain.records();
+        // The error bodies are rendered in the configured format.
+        assert!(sizes[1] > "409".len());
+        for ((_, kv), size) in records.iter().zip(sizes) {
+            assert_eq!(kv["bytes_out"], size.to_string());
+        }
+    }
+
+    #[test]
+    fn sampling_and_exclusions() {
+        let settings = Settings {
+            access_log: AccessLogSettings {
+                exclude: vec!["/fail".to_owned()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (drain, _) = records(settings, &["/item/1", "/fail", "/__lbheartbeat__"]);
+        assert_eq!(drain.records().len(), 2);
+
+        let settings = Settings {
+            access_log: AccessLogSettings {
+                sample_rate: 0.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (drain, _) = records(settings, &["/item/1", "/item/2"]);
+        assert!(drain.records().is_empty());
+    }
+}
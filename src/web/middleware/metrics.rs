@@ -0,0 +1,187 @@
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::StatusCode,
+    web::Data,
+    Error,
+};
+use futures::{future::LocalBoxFuture, FutureExt};
+use futures_util::future::{ok, Ready};
+
+use crate::{
+    metrics::Metrics,
+    server::ServerState,
+    tags::{Tags, URI_ROUTE},
+    web::DOCKER_FLOW_ENDPOINTS,
+};
+
+/// Timer recording the duration of every request
+pub const REQUEST_TIMER: &str = "http.server.duration";
+/// Counter incremented for every response
+pub const RESPONSE_COUNTER: &str = "http.server.response";
+
+/// Record a timer and a response counter for every request, tagged with the
+/// matched route, method, status class and user agent.
+pub struct MetricsWrapper {
+    exclude: Rc<Vec<String>>,
+}
+
+impl Default for MetricsWrapper {
+    /// Skip the Dockerflow endpoints, which are called frequently by ops.
+    fn default() -> Self {
+        Self::excluding(&DOCKER_FLOW_ENDPOINTS)
+    }
+}
+
+impl MetricsWrapper {
+    /// Record metrics for all requests except those to the given paths.
+    pub fn excluding(paths: &[&str]) -> Self {
+        Self {
+            exclude: Rc::new(paths.iter().map(|p| p.to_string()).collect()),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for MetricsWrapper
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = MetricsWrapperMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(MetricsWrapperMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            exclude: self.exclude.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct MetricsWrapperMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    exclude: Rc<Vec<String>>,
+}
+
+/// Reduce the status to its class (e.g. `2xx`) to limit cardinality.
+fn status_class(status: StatusCode) -> String {
+    format!("{}xx", status.as_u16() / 100)
+}
+
+fn emit(metrics: Metrics, start: Instant, mut tags: Tags, status: StatusCode) {
+    // Keep requests that didn't match any route in a single series.
+    tags.tags
+        .entry(URI_ROUTE.to_owned())
+        .or_insert_with(|| "unknown".to_owned());
+    tags.tags
+        .insert("http.status".to_owned(), status_class(status));
+    let lapse = start.elapsed().as_millis() as u64;
+    metrics.timing_with_tags(REQUEST_TIMER, lapse, Some(tags.clone()));
+    metrics.incr_with_tags(RESPONSE_COUNTER, Some(tags));
+}
+
+impl<S, B> Service<ServiceRequest> for MetricsWrapperMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, sreq: ServiceRequest) -> Self::Future {
+        let state = sreq.app_data::<Data<ServerState>>().cloned();
+        if state.is_none() || self.exclude.iter().any(|p| p == sreq.path()) {
+            return self.service.call(sreq).boxed_local();
+        }
+        let start = Instant::now();
+        // Only needed if the request fails without a response, but they're
+        // built (once) for each request anyway.
+        let request_tags = Tags::for_request(sreq.request());
+
+        let fut = self.service.call(sreq);
+
+        async move {
+            match fut.await {
+                Ok(resp) => {
+                    let mut tags = Tags::default();
+                    tags.add_route(resp.request());
+                    emit(Metrics::from(resp.request()), start, tags, resp.status());
+                    Ok(resp)
+                }
+                Err(err) => {
+                    if let Some(state) = state.as_ref() {
+                        emit(
+                            Metrics::from(state),
+                            start,
+                            request_tags,
+                            err.as_response_error().status_code(),
+                        );
+                    }
+                    Err(err)
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    use crate::{
+        metrics::MetricsBackend,
+        settings::{MetricsTagSettings, Settings},
+    };
+
+    #[actix_rt::test]
+    async fn records_route_and_status() {
+        let settings = Settings {
+            metrics_backend: MetricsBackend::Prometheus,
+            metrics_tags: MetricsTagSettings {
+                version: true,
+                ..Default::default()
+            },
+            ..Settings::default()
+        };
+        let state = Data::new(ServerState::from_settings(&settings).unwrap());
+        let app = init_service(
+            App::new()
+                .app_data(state.clone())
+                .wrap(MetricsWrapper::default())
+                .route("/item/{id}", web::get().to(HttpResponse::Ok))
+                .route("/__lbheartbeat__", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for path in ["/item/1", "/item/2", "/__lbheartbeat__"] {
+            call_service(&app, TestRequest::get().uri(path).to_request()).await;
+        }
+
+        let output = state.prometheus.as_ref().unwrap().render();
+        assert!(output.contains(&format!(
+            "skeleton_http_server_response{{http_status=\"2xx\",uri_method=\"GET\",uri_route=\"/item/{{id}}\",version=\"{}\"}} 2\n",
+            env!("CARGO_PKG_VERSION")
+        )));
+        assert!(output.contains("skeleton_http_server_duration_count{"));
+        assert!(!output.contains("__lbheartbeat__"));
+    }
+}
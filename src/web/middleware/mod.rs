@@ -1 +1,4 @@
+pub mod access_log;
+pub mod metrics;
+pub mod request_id;
 pub mod sentry;
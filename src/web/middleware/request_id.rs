@@ -0,0 +1,211 @@
+//! Identify each request, so that its log lines, Sentry events, error
+//! responses and upstream (e.g. NGINX) logs can be matched up.
+//!
+//! The ID is taken from the `request_id_header` (`X-Request-Id`) set by the
+//! load balancer, or generated when missing or malformed. It's stored in the
+//! request extensions as a [RequestId], included in the `Tags` extra data and
+//! the request's slog scope (see `RequestLogger`), and echoed in the
+//! response.
+use std::{
+    cell::RefCell,
+    fmt,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    web::Data,
+    Error, HttpMessage,
+};
+use futures::{future::LocalBoxFuture, FutureExt};
+use futures_util::future::{ok, Ready};
+
+use crate::{logging::WithLogger, server::ServerState, web::extractors::RequestLogger};
+
+/// The default header holding the request ID
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// The key of the request ID in the `Tags` extra data and log records
+pub const REQUEST_ID_KEY: &str = "request_id";
+
+/// Longer inbound IDs are replaced, to bound what a client can inject into
+/// the logs.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// The ID of the current request, available from its extensions.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// A random 128 bit ID, in the hex format used by NGINX's `$request_id`.
+    pub fn generate() -> Self {
+        Self(format!("{:032x}", rand::random::<u128>()))
+    }
+
+    /// Accept an inbound ID made of (a reasonable number of) URL safe
+    /// characters.
+    fn parse(value: &HeaderValue) -> Option<Self> {
+        let value = value.to_str().ok()?;
+        let valid = !value.is_empty()
+            && value.len() <= MAX_REQUEST_ID_LEN
+            && value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c));
+        valid.then(|| Self(value.to_owned()))
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Default)]
+pub struct RequestIdWrapper;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestIdWrapper
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestIdWrapperMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdWrapperMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct RequestIdWrapperMiddleware<S> {
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdWrapperMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, sreq: ServiceRequest) -> Self::Future {
+        let header = sreq
+            .app_data::<Data<ServerState>>()
+            .map(|state| state.request_id_header.clone())
+            .unwrap_or_else(|| HeaderName::from_static(REQUEST_ID_HEADER));
+        let request_id = sreq
+            .headers()
+            .get(&header)
+            .and_then(RequestId::parse)
+            .unwrap_or_else(RequestId::generate);
+        sreq.extensions_mut().insert(request_id.clone());
+
+        // This also builds the request's `Tags`, which the inner middleware
+        // and handlers then reuse.
+        let logger = RequestLogger::new(sreq.request());
+        sreq.extensions_mut().insert(logger.clone());
+        let fut = WithLogger::new(logger.0, self.service.call(sreq).boxed_local());
+
+        async move {
+            // (`HandlerError::render_error` includes the ID in error bodies.)
+            let mut resp = fut.await?;
+            if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+                resp.headers_mut().insert(header, value);
+            }
+            Ok(resp)
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        body::to_bytes,
+        middleware::ErrorHandlers,
+        test::{call_service, init_service, read_body_json, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    use crate::{
+        error::{ErrorFormat, HandlerError, HandlerErrorKind, HandlerResult},
+        settings::Settings,
+        tags::Tags,
+    };
+
+    async fn echo(tags: Tags) -> HttpResponse {
+        HttpResponse::Ok().body(tags.extra[REQUEST_ID_KEY].clone())
+    }
+
+    async fn fail() -> HandlerResult<String> {
+        Err(HandlerErrorKind::Conflict("busy".to_owned()).into())
+    }
+
+    #[actix_rt::test]
+    async fn request_ids() {
+        let settings = Settings {
+            request_id_header: "X-Trace-Id".to_owned(),
+            error_format: ErrorFormat::Json,
+            ..Default::default()
+        };
+        let state = ServerState::from_settings(&settings).unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .wrap(ErrorHandlers::new().default_handler(HandlerError::render_error))
+                .wrap(RequestIdWrapper)
+                .route("/echo", web::get().to(echo))
+                .route("/fail", web::get().to(fail)),
+        )
+        .await;
+
+        // An inbound ID is kept.
+        let req = TestRequest::get()
+            .uri("/echo")
+            .insert_header(("X-Trace-Id", "abc-123"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.headers().get("x-trace-id").unwrap(), "abc-123");
+        assert_eq!(to_bytes(resp.into_body()).await.unwrap(), "abc-123");
+
+        // A missing or malformed one is replaced.
+        for header in [None, Some("no spaces\tor tabs")] {
+            let mut req = TestRequest::get().uri("/echo");
+            if let Some(header) = header {
+                req = req.insert_header(("X-Trace-Id", header));
+            }
+            let resp = call_service(&app, req.to_request()).await;
+            let id = resp.headers().get("x-trace-id").unwrap().clone();
+            assert_eq!(id.len(), 32);
+            assert_eq!(to_bytes(resp.into_body()).await.unwrap(), id.as_bytes());
+        }
+
+        // Errors are rendered in the configured format, with the ID.
+        let req = TestRequest::get()
+            .uri("/fail")
+            .insert_header(("X-Trace-Id", "abc-456"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 409);
+        assert_eq!(resp.headers().get("x-trace-id").unwrap(), "abc-456");
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["errno"], 409);
+        assert_eq!(body["request_id"], "abc-456");
+    }
+}
@@ -1,20 +1,30 @@
 use std::{
     cell::{RefCell, RefMut},
     rc::Rc,
+    sync::Arc,
     task::Context,
 };
 
 use actix_http::Extensions;
 use actix_web::{
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header::HeaderMap, StatusCode},
+    web::Data,
     Error, HttpMessage,
 };
 use futures::{future::LocalBoxFuture, FutureExt};
 use futures_util::future::{ok, Ready};
-use sentry::protocol::Event;
+use sentry::{
+    protocol::{Event, SpanStatus},
+    Hub, SentryFutureExt, Transaction, TransactionContext,
+};
 use std::task::Poll;
 
-use crate::{error::HandlerError, tags::Tags};
+use crate::{
+    error::HandlerError,
+    server::{reporting::ERRNO_TAG, ServerState},
+    tags::Tags,
+};
 
 #[derive(Default)]
 pub struct SentryWrapper;
@@ -45,14 +55,12 @@ pub struct SentryWrapperMiddleware<S> {
 pub fn queue_report(mut ext: RefMut<'_, Extensions>, err: &Error) {
     let herr: Option<&HandlerError> = err.as_error();
     if let Some(herr) = herr {
-        /*
-        // example: Skip if the error shouldn't be reported
+        // Skip if the error shouldn't be reported
         if !herr.is_reportable() {
             trace!("Sentry Not reporting error: {:?}", err);
             return;
         }
-        */
-        let event = sentry::event_from_error(herr);
+        let event = error_event(herr);
         if let Some(events) = ext.get_mut::<Vec<Event<'static>>>() {
             events.push(event);
         } else {
@@ -62,14 +70,90 @@ pub fn queue_report(mut ext: RefMut<'_, Extensions>, err: &Error) {
     }
 }
 
+/// Build the event for an error, tagged with its errno for the
+/// `reporting::EventFilter`.
+fn error_event(herr: &HandlerError) -> Event<'static> {
+    let mut event = sentry::event_from_error(herr);
+    event
+        .tags
+        .insert(ERRNO_TAG.to_owned(), herr.kind().errno().to_string());
+    event
+}
+
 pub fn report(tags: &Tags, mut event: Event<'static>) {
     let tags = tags.clone();
-    event.tags = tags.clone().tag_tree();
-    event.extra = tags.extra_tree();
+    event.tags.extend(tags.clone().tag_tree());
+    event.extra.extend(tags.extra_tree());
     trace!("Sentry: Sending error: {:?}", &event);
     sentry::capture_event(event);
 }
 
+/// Call any special processing for a given error (e.g. record metrics), then
+/// report it to Sentry if need be.
+fn handle_error(state: Option<&Data<ServerState>>, tags: &Tags, herr: &HandlerError) {
+    if let Some(state) = state {
+        herr.on_response(state);
+    };
+    if !herr.is_reportable() {
+        trace!("Sentry: Not reporting error: {:?}", herr);
+        return;
+    }
+    report(tags, error_event(herr));
+}
+
+/// Start a performance monitoring transaction for the request, named by its
+/// route (e.g. `GET /1.5/{uid}/info`), continuing any trace from the incoming
+/// `sentry-trace` and `baggage` headers.
+fn start_transaction(hub: &Hub, sreq: &ServiceRequest) -> Transaction {
+    let name = format!(
+        "{} {}",
+        sreq.method(),
+        sreq.match_pattern().as_deref().unwrap_or("unknown")
+    );
+    let headers = sreq
+        .headers()
+        .iter()
+        .filter_map(|(key, val)| val.to_str().ok().map(|val| (key.as_str(), val)));
+    let mut ctx = TransactionContext::continue_from_headers(&name, "http.server", headers);
+    if ctx.sampled().is_none() {
+        ctx.set_sampled(baggage_sampled(sreq.headers()));
+    }
+    hub.start_transaction(ctx)
+}
+
+/// The upstream sampling decision (`sentry-sampled`) from the `baggage`
+/// header, used when `sentry-trace` doesn't include one.
+fn baggage_sampled(headers: &HeaderMap) -> Option<bool> {
+    headers
+        .get_all("baggage")
+        .filter_map(|val| val.to_str().ok())
+        .flat_map(|val| val.split(','))
+        .find_map(|entry| match entry.trim().split_once('=') {
+            Some(("sentry-sampled", sampled)) => sampled.trim().parse().ok(),
+            _ => None,
+        })
+}
+
+fn span_status(status: StatusCode) -> SpanStatus {
+    match status {
+        StatusCode::UNAUTHORIZED => SpanStatus::Unauthenticated,
+        StatusCode::FORBIDDEN => SpanStatus::PermissionDenied,
+        StatusCode::NOT_FOUND => SpanStatus::NotFound,
+        StatusCode::CONFLICT => SpanStatus::AlreadyExists,
+        StatusCode::TOO_MANY_REQUESTS => SpanStatus::ResourceExhausted,
+        StatusCode::SERVICE_UNAVAILABLE => SpanStatus::Unavailable,
+        status if status.is_client_error() => SpanStatus::InvalidArgument,
+        status if status.is_server_error() => SpanStatus::InternalError,
+        _ => SpanStatus::Ok,
+    }
+}
+
+fn finish_transaction(transaction: Transaction, status: StatusCode) {
+    transaction.set_status(span_status(status));
+    transaction.set_data("http.response.status_code", status.as_u16().into());
+    transaction.finish();
+}
+
 impl<S, B> Service<ServiceRequest> for SentryWrapperMiddleware<S>
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
@@ -84,21 +168,21 @@ where
     }
 
     fn call(&self, sreq: ServiceRequest) -> Self::Future {
-        let mut tags = Tags::from_request_head(sreq.head());
-        if let Some(rtags) = sreq.request().extensions().get::<Tags>() {
-            trace!("Sentry: found tags in request: {:?}", &rtags.tags);
-            for (k, v) in rtags.tags.clone() {
-                tags.tags.insert(k, v);
-            }
-        };
+        let tags = Tags::for_request(sreq.request());
+        let state = sreq.app_data::<Data<ServerState>>().cloned();
 
-        sreq.extensions_mut().insert(tags.clone());
+        // Each request gets its own hub, so that its transaction is the
+        // parent of any spans (e.g. `MetricTimer`s) started while handling it.
+        let hub = Arc::new(Hub::new_from_top(Hub::current()));
+        let transaction = start_transaction(&hub, &sreq);
+        hub.configure_scope(|scope| scope.set_span(Some(transaction.clone().into())));
 
-        let fut = self.service.call(sreq);
+        let fut = Hub::run(hub.clone(), || self.service.call(sreq)).bind_hub(hub.clone());
 
         async move {
             let resp: Self::Response = match fut.await {
                 Ok(resp) => {
+                    finish_transaction(transaction, resp.status());
                     if let Some(events) = resp
                         .request()
                         .extensions_mut()
@@ -109,24 +193,20 @@ where
                             report(&tags, event);
                         }
                     };
+                    // Errors returned by handlers are rendered into the response.
+                    if let Some(herr) = resp
+                        .response()
+                        .error()
+                        .and_then(|err| err.as_error::<HandlerError>())
+                    {
+                        handle_error(state.as_ref(), &tags, herr);
+                    }
                     resp
                 }
                 Err(err) => {
+                    finish_transaction(transaction, err.as_response_error().status_code());
                     if let Some(herr) = err.as_error::<HandlerError>() {
-                        /*
-                        // Call any special processing for a given error (e.g. record metrics)
-                        if let Some(state) = sresp.request().app_data::<Data<ServerState>>() {
-                            herr.on_response(state.as_ref());
-                        };
-                        */
-                        /*
-                        // skip reporting error if need be
-                        if !herr.is_reportable() {
-                            trace!("Sentry: Not reporting error: {:?}", herr);
-                            return future::ok(sresp);
-                        }
-                        */
-                        report(&tags, sentry::event_from_error(herr));
+                        handle_error(state.as_ref(), &tags, herr);
                     };
                     return Err(err);
                 }
@@ -134,6 +214,174 @@ where
 
             Ok(resp)
         }
+        .bind_hub(hub)
         .boxed_local()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpRequest, HttpResponse,
+    };
+    use sentry::{
+        protocol::{Context, EnvelopeItem},
+        ClientOptions,
+    };
+
+    use crate::{
+        error::{HandlerErrorKind, HandlerResult},
+        metrics::Metrics,
+        settings::Settings,
+    };
+
+    const TRACE_ID: &str = "771a43a4192642f0b136d5159a501700";
+
+    async fn fail(kind: web::Path<String>) -> HandlerResult<String> {
+        let kind = match kind.as_str() {
+            "bad_request" => HandlerErrorKind::BadRequest("bad".to_owned()),
+            "not_found" => HandlerErrorKind::NotFound("gone".to_owned()),
+            "conflict" => HandlerErrorKind::Conflict("busy".to_owned()),
+            "too_many" => HandlerErrorKind::TooManyRequests(Some(10)),
+            _ => HandlerErrorKind::Internal("oops".to_owned()),
+        };
+        Err(kind.into())
+    }
+
+    fn captured_events(paths: &[&str]) -> Vec<Event<'static>> {
+        sentry::test::with_captured_events(|| {
+            actix_rt::System::new().block_on(async {
+                let state = ServerState::from_settings(&Settings::default()).unwrap();
+                let app = init_service(
+                    App::new()
+                        .app_data(Data::new(state))
+                        .wrap(SentryWrapper)
+                        .route("/fail/{kind}", web::get().to(fail)),
+                )
+                .await;
+                for path in paths {
+                    let req = TestRequest::get().uri(path).to_request();
+                    call_service(&app, req).await;
+                }
+            })
+        })
+    }
+
+    #[test]
+    fn client_errors_not_reported() {
+        let events = captured_events(&[
+            "/fail/bad_request",
+            "/fail/not_found",
+            "/fail/conflict",
+            "/fail/too_many",
+        ]);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn server_errors_reported() {
+        let events = captured_events(&["/fail/conflict", "/fail/internal"]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].tags.get("uri.method").unwrap(), "GET");
+        assert_eq!(events[0].tags.get("uri.route").unwrap(), "/fail/{kind}");
+        assert_eq!(events[0].tags.get(ERRNO_TAG).unwrap(), "510");
+        assert_eq!(events[0].extra.get("uri.path").unwrap(), "/fail/internal");
+    }
+
+    #[test]
+    fn client_error_kinds_not_reportable() {
+        let kinds = [
+            HandlerErrorKind::BadRequest("".to_owned()),
+            HandlerErrorKind::NotFound("".to_owned()),
+            HandlerErrorKind::Unauthorized("".to_owned(), "Bearer".to_owned()),
+            HandlerErrorKind::Forbidden("".to_owned()),
+            HandlerErrorKind::Conflict("".to_owned()),
+            HandlerErrorKind::PayloadTooLarge("".to_owned()),
+            HandlerErrorKind::TooManyRequests(None),
+        ];
+        for kind in kinds {
+            assert!(kind.http_status().is_client_error());
+            assert!(!kind.is_reportable(), "{:?} is reportable", kind);
+            assert!(kind.metric_label().is_some());
+        }
+        assert!(!HandlerErrorKind::ServiceUnavailable("".to_owned(), None).is_reportable());
+        assert!(HandlerErrorKind::Internal("".to_owned()).is_reportable());
+        assert!(HandlerErrorKind::General("".to_owned()).is_reportable());
+    }
+
+    async fn timed(req: HttpRequest) -> HttpResponse {
+        Metrics::from(&req).start_timer("db.query", None).finish();
+        HttpResponse::Ok().finish()
+    }
+
+    #[test]
+    fn request_transactions() {
+        let envelopes = sentry::test::with_captured_envelopes_options(
+            || {
+                actix_rt::System::new().block_on(async {
+                    let state = ServerState::from_settings(&Settings::default()).unwrap();
+                    let app = init_service(
+                        App::new()
+                            .app_data(Data::new(state))
+                            .wrap(SentryWrapper)
+                            .route("/item/{id}", web::get().to(timed)),
+                    )
+                    .await;
+                    let requests = [
+                        // Sampled upstream
+                        vec![("sentry-trace", format!("{}-b0e6f15b45c36b12-1", TRACE_ID))],
+                        // Sampled according to the baggage
+                        vec![
+                            ("sentry-trace", format!("{}-b0e6f15b45c36b13", TRACE_ID)),
+                            ("baggage", "other=1, sentry-sampled=true".to_owned()),
+                        ],
+                        // Not sampled upstream
+                        vec![("sentry-trace", format!("{}-b0e6f15b45c36b14-0", TRACE_ID))],
+                        // Not sampled by our `traces_sample_rate`
+                        vec![],
+                    ];
+                    for headers in requests {
+                        let mut req = TestRequest::get().uri("/item/1");
+                        for header in headers {
+                            req = req.insert_header(header);
+                        }
+                        call_service(&app, req.to_request()).await;
+                    }
+                })
+            },
+            ClientOptions {
+                traces_sample_rate: 0.0,
+                ..Default::default()
+            },
+        );
+
+        let transactions: Vec<_> = envelopes
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .filter_map(|item| match item {
+                EnvelopeItem::Transaction(transaction) => Some(transaction),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(transactions.len(), 2);
+        let parents = ["b0e6f15b45c36b12", "b0e6f15b45c36b13"];
+        for (transaction, parent) in transactions.iter().zip(parents) {
+            assert_eq!(transaction.name.as_deref(), Some("GET /item/{id}"));
+            let Some(Context::Trace(trace)) = transaction.contexts.get("trace") else {
+                panic!("Missing trace context");
+            };
+            assert_eq!(trace.trace_id.to_string(), TRACE_ID);
+            assert_eq!(trace.parent_span_id.unwrap().to_string(), parent);
+            assert_eq!(trace.op.as_deref(), Some("http.server"));
+            assert_eq!(trace.status, Some(SpanStatus::Ok));
+            assert_eq!(transaction.spans.len(), 1);
+            assert_eq!(transaction.spans[0].op.as_deref(), Some("metrics.timer"));
+            assert_eq!(
+                transaction.spans[0].description.as_deref(),
+                Some("db.query")
+            );
+        }
+    }
+}
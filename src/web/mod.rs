@@ -1,11 +1,14 @@
 //! Web authentication, handlers, and middleware
+pub mod client_ip;
 pub mod extractors;
+pub mod geoip;
 pub mod middleware;
 
 // Known DockerFlow commands for Ops callbacks
-pub const DOCKER_FLOW_ENDPOINTS: [&str; 4] = [
+pub const DOCKER_FLOW_ENDPOINTS: [&str; 5] = [
     "/__heartbeat__",
     "/__lbheartbeat__",
     "/__version__",
     "/__error__",
+    "/__metrics__",
 ];
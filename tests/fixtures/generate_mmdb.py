@@ -0,0 +1,137 @@
+#!/usr/bin/env python3
+"""Generate the GeoIP test databases in this directory.
+
+Writes minimal IPv4 MaxMind DB (format 2.0) files, mapping 192.0.2.0/24
+(TEST-NET-1, RFC 5737) to a made up city record in the GeoIP2-City layout.
+Only the standard library is used, so it can be run from anywhere:
+
+    python3 tests/fixtures/generate_mmdb.py
+
+See https://maxmind.github.io/MaxMind-DB/ for the format.
+"""
+import ipaddress
+import os
+import struct
+
+STR, MAP, U16, U32, U64, ARRAY = 2, 7, 5, 6, 9, 11
+
+
+def encode(value, out):
+    """Append a data section value, given as (type, payload), to `out`."""
+    kind, payload = value
+
+    def control(size):
+        assert size < 29
+        if kind > 7:
+            # Extended types: the type follows the control byte.
+            out.extend([size, kind - 7])
+        else:
+            out.append((kind << 5) | size)
+
+    if kind == STR:
+        control(len(payload))
+        out.extend(payload.encode())
+    elif kind == U16:
+        control(2)
+        out.extend(struct.pack(">H", payload))
+    elif kind == U32:
+        control(4)
+        out.extend(struct.pack(">I", payload))
+    elif kind == U64:
+        control(8)
+        out.extend(struct.pack(">Q", payload))
+    elif kind == MAP:
+        control(len(payload))
+        for key, item in payload:
+            encode((STR, key), out)
+            encode(item, out)
+    elif kind == ARRAY:
+        control(len(payload))
+        for item in payload:
+            encode(item, out)
+
+
+def build_database(networks):
+    """A database mapping each (network, value) to its value."""
+    # Each node is a pair of records: None (empty), ("node", n) or
+    # ("data", offset).
+    nodes = [[None, None]]
+    data = bytearray()
+    for network, value in networks:
+        offset = len(data)
+        encode(value, data)
+        addr = int(network.network_address)
+        prefix = network.prefixlen
+        node = 0
+        for depth in range(prefix):
+            bit = (addr >> (31 - depth)) & 1
+            if depth == prefix - 1:
+                nodes[node][bit] = ("data", offset)
+                break
+            record = nodes[node][bit]
+            if record is not None and record[0] == "node":
+                node = record[1]
+            else:
+                nodes.append([None, None])
+                nodes[node][bit] = ("node", len(nodes) - 1)
+                node = len(nodes) - 1
+
+    node_count = len(nodes)
+    db = bytearray()
+    for node in nodes:
+        for record in node:
+            if record is None:
+                value = node_count
+            elif record[0] == "node":
+                value = record[1]
+            else:
+                value = node_count + 16 + record[1]
+            db.extend(struct.pack(">I", value)[1:])
+    db.extend(bytes(16))
+    db.extend(data)
+    db.extend(b"\xab\xcd\xefMaxMind.com")
+    encode(
+        (
+            MAP,
+            [
+                ("binary_format_major_version", (U16, 2)),
+                ("binary_format_minor_version", (U16, 0)),
+                ("build_epoch", (U64, 0)),
+                ("database_type", (STR, "Skeleton-City-Test")),
+                ("description", (MAP, [])),
+                ("ip_version", (U16, 4)),
+                ("languages", (ARRAY, [(STR, "en")])),
+                ("node_count", (U32, node_count)),
+                ("record_size", (U16, 24)),
+            ],
+        ),
+        db,
+    )
+    return bytes(db)
+
+
+def city(country, subdivision, name):
+    def names(value):
+        return (MAP, [("names", (MAP, [("en", (STR, value))]))])
+
+    return (
+        MAP,
+        [
+            ("country", (MAP, [("iso_code", (STR, country))])),
+            ("subdivisions", (ARRAY, [names(subdivision)])),
+            ("city", names(name)),
+        ],
+    )
+
+
+FIXTURES = {
+    "GeoIP2-City-Test.mmdb": city("CA", "Ontario", "Toronto"),
+    "GeoIP2-City-Test-Updated.mmdb": city("DE", "Berlin", "Berlin"),
+}
+
+if __name__ == "__main__":
+    out_dir = os.environ.get("OUT_DIR", os.path.dirname(os.path.abspath(__file__)))
+    for filename, value in FIXTURES.items():
+        network = ipaddress.ip_network("192.0.2.0/24")
+        with open(os.path.join(out_dir, filename), "wb") as f:
+            f.write(build_database([(network, value)]))